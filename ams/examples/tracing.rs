@@ -0,0 +1,42 @@
+//! Subscribes to the diagnostics emitted by AMS and prints them to stdout.
+//!
+//! Every connection task runs in a `connection` span keyed by the peer address, so the frames and lifecycle events
+//! of a single peer can be correlated. Run with `cargo run --example tracing`.
+use std::{net::SocketAddr, time::Duration};
+
+use ams::{Ams, Event};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    let a_addr: SocketAddr = "127.0.0.1:7401".parse().unwrap();
+    let b_addr: SocketAddr = "127.0.0.1:7402".parse().unwrap();
+    let mut a = Ams::bind(a_addr).await?;
+    let mut b = Ams::bind(b_addr).await?;
+
+    // Accept every incoming connection on `b`.
+    let accept = tokio::spawn(async move {
+        while let Some(event) = b.next_event().await {
+            if let Event::ConnectionRequested { response, .. } = event {
+                let _ = response.send(true);
+            }
+        }
+    });
+
+    a.connect(b_addr).await?;
+    if let Some(Event::ConnectionEstablished { peer, .. }) = a.next_event().await {
+        a.send_message(peer, b"Hello, world!".to_vec()).await?;
+        a.next_event_timeout(Duration::from_secs(1)).await;
+        // Give `b` a moment to receive the message before hanging up.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        a.disconnect(peer).await?;
+        a.next_event_timeout(Duration::from_secs(1)).await;
+    }
+
+    a.shutdown().await;
+    accept.abort();
+    Ok(())
+}
@@ -1,7 +1,7 @@
 //! This module contains the message structs that can be sent and received by clients.
 //!
 //! AMS supports both peer to peer and client-server architectures. Messages being sent through a server must be
-//! wrapped in a `Server` message, while peer to peer messages can be sent directly as is.
+//! wrapped in a [Server] message, while peer to peer messages can be sent directly as is.
 use serde_derive::*;
 
 /// A command to send a message to another client.
@@ -14,3 +14,96 @@ pub struct Message {
     /// The sender connection id // SocketAddr -> String
     pub sender: String,
 }
+
+/// A message to be relayed by a server to another client connected to it.
+#[derive(Serialize, Deserialize)]
+pub struct Server {
+    /// The client the message is relayed to.
+    ///
+    /// Clients behind a server are addressed by the socket address of their connection, as seen by the server.
+    pub recipient: String,
+    /// The wrapped message, delivered to the recipient as is.
+    pub message: Message,
+}
+
+/// A message sent to every member of a room.
+#[derive(Serialize, Deserialize)]
+pub struct Room {
+    /// The name of the room
+    pub room: String,
+    /// The wrapped message
+    pub message: Message,
+}
+
+/// A reaction to a message previously received from a client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    /// The id of the message reacted to
+    pub message_id: u64,
+    /// The emoji reacted with
+    pub emoji: String,
+}
+
+/// The nickname and status a client advertises to its peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+    /// The name the client goes by
+    pub nickname: String,
+    /// Whether the client is available
+    pub status: Status,
+}
+
+/// The availability of a client, advertised as part of its [Presence].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    /// Available
+    Online,
+    /// Connected, but not at the keyboard
+    Away,
+    /// Connected, but not to be disturbed
+    Busy,
+}
+
+/// A part of a file transferred to a client.
+///
+/// A transfer starts with [Transfer::Start], followed by the file's content split in [Transfer::Chunk]s and ends with
+/// [Transfer::End]. The receiving client accepts the file with [Transfer::Accepted] before its content is sent, and
+/// answers with [Transfer::Result] once it verified the file, or refused it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transfer {
+    /// Announces a file
+    Start {
+        /// The unique id of the transfer
+        id: u64,
+        /// The name of the file, without its directory
+        name: String,
+        /// The size of the file, in bytes
+        size: u64,
+    },
+    /// A part of the file's content, following the previous one
+    Chunk {
+        /// The unique id of the transfer
+        id: u64,
+        /// The content
+        data: Vec<u8>,
+    },
+    /// Ends the file's content
+    End {
+        /// The unique id of the transfer
+        id: u64,
+        /// The SHA-256 checksum of the file's content
+        checksum: [u8; 32],
+    },
+    /// Whether the file was received intact
+    Result {
+        /// The unique id of the transfer
+        id: u64,
+        /// Whether the receiving client verified the file and kept it
+        ok: bool,
+    },
+    /// Accepts the announced file, so its content may be sent
+    Accepted {
+        /// The unique id of the transfer
+        id: u64,
+    },
+}
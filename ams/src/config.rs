@@ -0,0 +1,492 @@
+//! Configuration for an AMS instance.
+use std::{collections::HashSet, net::IpAddr, time::Duration};
+
+#[cfg(feature = "tls")]
+use crate::transport::TlsConfig;
+use crate::{
+    controller::StackKind,
+    format::MessageFormat,
+    layers::{auth::Token, fragment::FragmentLimits},
+    transport::CodecConfig,
+};
+
+/// Tunables for an AMS instance, provided to [crate::Ams::bind_with].
+///
+/// Either set the fields directly, starting from [AmsConfig::default], or use [AmsConfig::builder].
+#[derive(Debug, Clone)]
+pub struct AmsConfig {
+    /// The number of events buffered for the consumer of [crate::Ams::next_event] beyond which events are dropped.
+    ///
+    /// Events are emitted without waiting on the consumer, so a consumer that stops draining events cannot stall the
+    /// instance. Once the buffer is full, newly emitted events are dropped until the consumer catches up, except for
+    /// those reporting the outcome of a connection, message or file transfer, such as
+    /// [crate::Event::ConnectionRequested] or [crate::Event::MessageSent], which are always emitted. Dropped events
+    /// are logged and counted in [crate::AmsStats::events_dropped].
+    pub event_capacity: usize,
+    /// How long [crate::Ams::connect] waits for the remote peer to accept the TCP connection before emitting
+    /// [crate::Event::ConnectionRejected].
+    pub connect_timeout: Duration,
+    /// How long a connection may take to be established once its TCP connection is open, including the TLS and codec
+    /// handshakes and the initialization of its controller stack, before it is rejected with
+    /// [crate::Event::ConnectionRejected].
+    pub handshake_timeout: Duration,
+    /// How long a connection may go without receiving a frame or processing a command before it is disconnected with
+    /// [crate::DisconnectReason::Timeout].
+    ///
+    /// Disabled (`None`) by default.
+    pub idle_timeout: Option<Duration>,
+    /// The policy consulted before an inbound connection is offered to the application via
+    /// [crate::Event::ConnectionRequested].
+    pub accept_policy: AcceptPolicy,
+    /// How the inbound connections permitted by the [AmsConfig::accept_policy] are accepted.
+    pub inbound_mode: InboundMode,
+    /// The rate inbound connections are accepted at, beyond which they are closed as soon as they are accepted.
+    ///
+    /// Connections over the limit are closed before the [AmsConfig::accept_policy] is consulted, without spawning a
+    /// task nor emitting an event, so a flood of connections costs as little as possible. They are reported through
+    /// `tracing`, at most once per second. Disabled (`None`) by default.
+    pub accept_rate_limit: Option<AcceptRateLimit>,
+    /// The name this instance introduces itself with to every peer it connects to, reported by
+    /// [crate::Event::ConnectionEstablished] on the peer's side. Only exchanged by controller stacks including an
+    /// [crate::layers::identity::Identity] layer, such as the default one.
+    ///
+    /// Disabled (`None`) by default. A nickname that is empty or longer than
+    /// [crate::layers::identity::MAX_NICKNAME_LEN] bytes is ignored by peers.
+    pub nickname: Option<String>,
+    /// The token shared by the peers allowed to connect to this instance, proven by the
+    /// [crate::layers::auth::Auth] layer of every built-in stack before a connection is established.
+    ///
+    /// Disabled (`None`) by default, in which case only peers without a token are allowed to connect.
+    pub auth_token: Option<Token>,
+    /// The controller stack used by inbound connections and by [crate::Ams::connect].
+    pub stack: StackKind,
+    /// The serialization format messages are exchanged with, which remote peers must be configured with too. See
+    /// [crate::format] for how the formats compare.
+    pub format: MessageFormat,
+    /// How long [crate::Ams::ping] waits for the remote peer to answer.
+    pub ping_timeout: Duration,
+    /// Whether Nagle's algorithm is disabled on the TCP stream of each connection, so small frames are sent right
+    /// away rather than batched.
+    ///
+    /// Enabled by default, as interactive messages are small and latency sensitive.
+    pub tcp_nodelay: bool,
+    /// The TCP keepalive probes sent on the TCP stream of each connection, letting the operating system detect a peer
+    /// that went away without closing the connection, e.g. behind a NAT that silently dropped an idle mapping.
+    ///
+    /// Disabled (`None`) by default. Keepalive probes carry no application data, so unlike a heartbeat sent by a layer
+    /// (e.g. [crate::layers::ping::Ping]) they do not keep an [AmsConfig::idle_timeout] from elapsing. Both can be used
+    /// together: keepalive to detect dead peers and keep NAT mappings open, and the layer to detect unresponsive
+    /// ones.
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// Whether `SO_REUSEADDR` is set on the listeners, so an instance can bind again right after a previous one
+    /// bound to the same address shut down.
+    ///
+    /// Enabled by default except on Windows, where the option lets another socket take over a bound address.
+    pub reuse_address: bool,
+    /// The maximum number of inbound connections queued by the operating system until they are accepted.
+    pub listen_backlog: u32,
+    /// How often a [crate::Event::Throughput] event is emitted for each connection, which must not be zero.
+    ///
+    /// Disabled (`None`) by default. Throughput is reported per interval rather than per frame, so the cost of
+    /// monitoring does not grow with the traffic: the connections only update counters, which the manager reads once
+    /// per interval.
+    pub throughput_interval: Option<Duration>,
+    /// The number of messages remembered for each connection to suppress duplicates, e.g. a message relayed twice.
+    ///
+    /// A message received again while remembered is not reported with [crate::Event::MessageReceived]. Messages are
+    /// told apart by their id and original sender. The window is cleared when the connection is disconnected, since a
+    /// restarted peer reuses message ids. Disabled with `0`.
+    pub dedup_window: usize,
+    /// The largest file, in bytes, received from a peer through a [crate::layers::file::File] layer. Larger files are
+    /// refused before any of their content is sent.
+    pub max_file_size: u64,
+    /// Whether each file offered by a peer is offered to the application with [crate::Event::FileOffered], and only
+    /// received if the application answers `true`.
+    ///
+    /// Disabled by default: files within the [AmsConfig::max_file_size] are received without asking.
+    pub prompt_files: bool,
+    /// How long a file transfer may wait on either peer before it fails: the sending peer waits this long for the file
+    /// to be accepted, and the receiving peer for each part of its content.
+    pub transfer_timeout: Duration,
+    /// The length prefix of the frames exchanged with remote peers.
+    pub codec: CodecConfig,
+    /// The limits of the fragments sent and received by controller stacks including a
+    /// [crate::layers::fragment::Fragment] layer, which remote peers must be configured with too.
+    pub fragment_limits: FragmentLimits,
+    /// The TLS configuration of inbound and outbound connections.
+    ///
+    /// Connections are carried over plain TCP unless configured otherwise.
+    #[cfg(feature = "tls")]
+    pub tls: TlsConfig,
+    /// The runtime the manager and connection tasks are spawned on.
+    ///
+    /// Defaults (`None`) to the runtime [crate::Ams::bind_with] is called from. Every timer of the instance is a
+    /// [tokio::time] timer, so it follows the clock of this runtime, including one paused with
+    /// `#[tokio::test(start_paused = true)]` or `tokio::time::pause`.
+    pub runtime: Option<tokio::runtime::Handle>,
+}
+
+impl Default for AmsConfig {
+    fn default() -> Self {
+        Self {
+            event_capacity: 1024,
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: None,
+            accept_policy: AcceptPolicy::default(),
+            inbound_mode: InboundMode::default(),
+            accept_rate_limit: None,
+            nickname: None,
+            auth_token: None,
+            stack: StackKind::default(),
+            format: MessageFormat::default(),
+            ping_timeout: Duration::from_secs(5),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            reuse_address: !cfg!(windows),
+            listen_backlog: 1024,
+            throughput_interval: None,
+            dedup_window: 1024,
+            max_file_size: 1024 * 1024 * 1024,
+            prompt_files: false,
+            transfer_timeout: Duration::from_secs(30),
+            codec: CodecConfig::default(),
+            fragment_limits: FragmentLimits::default(),
+            #[cfg(feature = "tls")]
+            tls: TlsConfig::default(),
+            runtime: None,
+        }
+    }
+}
+
+impl AmsConfig {
+    /// Returns a builder starting from the default configuration.
+    pub fn builder() -> AmsConfigBuilder {
+        AmsConfigBuilder::default()
+    }
+
+    /// Spawns a task of the instance on [Self::runtime].
+    pub(crate) fn spawn<F>(&self, task: F) -> tokio::task::JoinHandle<()>
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        match &self.runtime {
+            Some(runtime) => runtime.spawn(task),
+            None => tokio::spawn(task),
+        }
+    }
+}
+
+/// A builder for [AmsConfig], created with [AmsConfig::builder].
+///
+/// Every tunable left unset keeps its default value.
+#[derive(Debug, Clone, Default)]
+pub struct AmsConfigBuilder {
+    config: AmsConfig,
+}
+
+impl AmsConfigBuilder {
+    /// Sets [AmsConfig::event_capacity].
+    pub fn event_capacity(mut self, capacity: usize) -> Self {
+        self.config.event_capacity = capacity;
+        self
+    }
+
+    /// Sets [AmsConfig::connect_timeout].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets [AmsConfig::handshake_timeout].
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = timeout;
+        self
+    }
+
+    /// Enables [AmsConfig::idle_timeout].
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [CodecConfig::max_frame_length].
+    pub fn max_frame_length(mut self, len: usize) -> Self {
+        self.config.codec.max_frame_length = len;
+        self
+    }
+
+    /// Enables [AmsConfig::tcp_keepalive].
+    pub fn tcp_keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.config.tcp_keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets [AmsConfig::accept_policy].
+    pub fn accept_policy(mut self, policy: AcceptPolicy) -> Self {
+        self.config.accept_policy = policy;
+        self
+    }
+
+    /// Sets [AmsConfig::inbound_mode].
+    pub fn inbound_mode(mut self, mode: InboundMode) -> Self {
+        self.config.inbound_mode = mode;
+        self
+    }
+
+    /// Enables [AmsConfig::accept_rate_limit].
+    pub fn accept_rate_limit(mut self, limit: AcceptRateLimit) -> Self {
+        self.config.accept_rate_limit = Some(limit);
+        self
+    }
+
+    /// Sets [AmsConfig::nickname].
+    pub fn nickname(mut self, nickname: impl Into<String>) -> Self {
+        self.config.nickname = Some(nickname.into());
+        self
+    }
+
+    /// Enables [AmsConfig::auth_token].
+    pub fn auth_token(mut self, token: impl Into<Vec<u8>>) -> Self {
+        self.config.auth_token = Some(Token::new(token));
+        self
+    }
+
+    /// Sets [AmsConfig::stack].
+    pub fn stack(mut self, stack: StackKind) -> Self {
+        self.config.stack = stack;
+        self
+    }
+
+    /// Sets [AmsConfig::format].
+    pub fn format(mut self, format: MessageFormat) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    /// Sets [AmsConfig::ping_timeout].
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.config.ping_timeout = timeout;
+        self
+    }
+
+    /// Sets [AmsConfig::tcp_nodelay].
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.config.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Sets [AmsConfig::reuse_address].
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.config.reuse_address = reuse;
+        self
+    }
+
+    /// Sets [AmsConfig::listen_backlog].
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.config.listen_backlog = backlog;
+        self
+    }
+
+    /// Sets [AmsConfig::dedup_window].
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.config.dedup_window = window;
+        self
+    }
+
+    /// Enables [AmsConfig::throughput_interval].
+    pub fn throughput_interval(mut self, interval: Duration) -> Self {
+        self.config.throughput_interval = Some(interval);
+        self
+    }
+
+    /// Sets [AmsConfig::max_file_size].
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.config.max_file_size = size;
+        self
+    }
+
+    /// Sets [AmsConfig::prompt_files].
+    pub fn prompt_files(mut self, prompt: bool) -> Self {
+        self.config.prompt_files = prompt;
+        self
+    }
+
+    /// Sets [AmsConfig::transfer_timeout].
+    pub fn transfer_timeout(mut self, timeout: Duration) -> Self {
+        self.config.transfer_timeout = timeout;
+        self
+    }
+
+    /// Sets [AmsConfig::codec], including its [CodecConfig::max_frame_length].
+    pub fn codec(mut self, codec: CodecConfig) -> Self {
+        self.config.codec = codec;
+        self
+    }
+
+    /// Sets [AmsConfig::fragment_limits].
+    pub fn fragment_limits(mut self, limits: FragmentLimits) -> Self {
+        self.config.fragment_limits = limits;
+        self
+    }
+
+    /// Sets the TLS configuration of inbound and outbound connections.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.config.tls = tls;
+        self
+    }
+
+    /// Sets [AmsConfig::runtime].
+    pub fn runtime(mut self, runtime: tokio::runtime::Handle) -> Self {
+        self.config.runtime = Some(runtime);
+        self
+    }
+
+    /// Returns the configuration.
+    pub fn build(self) -> AmsConfig {
+        self.config
+    }
+}
+
+/// A policy for automatically rejecting inbound connections.
+///
+/// An inbound connection denied by the policy is never offered to the application; it is closed and a
+/// [crate::Event::ConnectionRejected] is emitted instead. When the policy permits the connection, the
+/// [AmsConfig::inbound_mode] decides whether it is accepted. The default policy permits every connection.
+#[derive(Debug, Clone, Default)]
+pub struct AcceptPolicy {
+    /// If set, only inbound connections from these addresses are permitted.
+    pub allowlist: Option<HashSet<IpAddr>>,
+    /// If set, inbound connections are denied while this many connections are already established. Connections still
+    /// being established are not counted.
+    pub max_connections: Option<usize>,
+}
+
+impl AcceptPolicy {
+    /// Returns true if an inbound connection from `ip`, or from a Unix domain socket peer if `None`, is permitted while
+    /// `connections` connections are established.
+    pub(crate) fn permits(&self, ip: Option<IpAddr>, connections: usize) -> bool {
+        ip.zip(self.allowlist.as_ref())
+            .is_none_or(|(ip, allowlist)| allowlist.contains(&ip))
+            && self.max_connections.is_none_or(|max| connections < max)
+    }
+}
+
+/// How inbound connections are accepted, see [AmsConfig::inbound_mode].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InboundMode {
+    /// Each connection is offered to the application with [crate::Event::ConnectionRequested], and accepted if the
+    /// application answers `true`.
+    #[default]
+    Prompt,
+    /// Every connection is accepted without asking the application, which is only notified once the connection is
+    /// established with [crate::Event::ConnectionEstablished].
+    AcceptAll,
+    /// Every connection is rejected with [crate::Event::ConnectionRejected].
+    RejectAll,
+}
+
+/// The limits of [AmsConfig::accept_rate_limit], each a token bucket whose rate and burst must not be zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptRateLimit {
+    /// The rate inbound connections are accepted at from any single IP address.
+    pub per_ip: Rate,
+    /// The rate inbound connections are accepted at overall.
+    pub global: Rate,
+}
+
+impl Default for AcceptRateLimit {
+    fn default() -> Self {
+        Self {
+            per_ip: Rate {
+                per_sec: 5,
+                burst: 10,
+            },
+            global: Rate {
+                per_sec: 100,
+                burst: 200,
+            },
+        }
+    }
+}
+
+impl AcceptRateLimit {
+    /// Returns an error if either rate would refuse every connection.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (name, rate) in [("per IP", self.per_ip), ("global", self.global)] {
+            if rate.per_sec == 0 || rate.burst == 0 {
+                return Err(format!("{name} accept rate and burst must not be zero"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A rate enforced with a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    /// The number of events allowed per second, on average.
+    pub per_sec: u32,
+    /// The number of events allowed at once, after a quiet period.
+    pub burst: u32,
+}
+
+/// The TCP keepalive settings of [AmsConfig::tcp_keepalive].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// How long the connection must be idle before the first probe is sent.
+    pub idle: Duration,
+    /// How long to wait for a probe to be answered before sending the next one, if not the operating system's default.
+    ///
+    /// Ignored on platforms where it cannot be configured, e.g. OpenBSD.
+    pub interval: Option<Duration>,
+    /// How many unanswered probes close the connection, if not the operating system's default.
+    ///
+    /// Ignored on platforms where it cannot be configured, e.g. OpenBSD.
+    pub retries: Option<u32>,
+}
+
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: None,
+            retries: None,
+        }
+    }
+}
+
+impl TcpKeepalive {
+    /// Enables keepalive with these settings on the TCP stream.
+    pub(crate) fn apply(&self, stream: &tokio::net::TcpStream) -> std::io::Result<()> {
+        #[allow(unused_mut)]
+        let mut keepalive = socket2::TcpKeepalive::new().with_time(self.idle);
+        // The platforms where socket2 lets the probe interval and count be configured.
+        #[cfg(any(
+            target_os = "android",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "ios",
+            target_os = "visionos",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "tvos",
+            target_os = "watchos",
+            target_os = "cygwin",
+            target_os = "windows",
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(retries) = self.retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+        }
+        socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+}
@@ -1,12 +1,47 @@
 //! A module for managing connections to remote AMS peers.
-use std::{any::Any, net::SocketAddr};
+use std::{
+    any::Any,
+    collections::VecDeque,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
 
-use futures_util::sink::SinkExt;
-use tokio::{net::TcpStream, sync::mpsc};
+use bytes::{Bytes, BytesMut};
+use futures_util::{FutureExt, sink::SinkExt, stream::SplitSink};
+use tokio::{sync::mpsc, time::Instant};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::Instrument;
 
-use crate::{Command, controller::Controller};
+use crate::{
+    AmsConfig, Command, DisconnectReason, FailureReason,
+    controller::{DynController, Processed, StackKind},
+    layers::{Dropped, Init, Signal},
+    stats::{ConnectionStats, Counters},
+    transport::{Handshake, Io, Side, Transport},
+};
+
+#[cfg(test)]
+mod memory;
+#[cfg(test)]
+mod step;
+
+/// The most high priority commands processed in a row while commands of a lower priority are queued, so control
+/// traffic cannot starve messages.
+const MAX_HIGH_PRIORITY_STREAK: usize = 8;
+/// The number of bulk commands that may be queued for a connection at once.
+const BULK_CAPACITY: usize = 4;
+/// The most replies to incoming frames waiting to be written before the connection stops reading frames, in case the
+/// remote peer stopped reading.
+const MAX_PENDING_REPLIES: usize = 32;
+/// How long a connection disconnected locally may spend writing its queued commands and goodbye, in case the remote
+/// peer stopped reading.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// A connection to a remote AMS peer.
 ///
@@ -30,94 +65,651 @@ use crate::{Command, controller::Controller};
 /// layers) that are supported by the AMS system. Controllers are first and for-most used for maintainability of the
 /// codebase, It helps ensure the decoupling of functionality while also being a point of abstraction for future
 /// features The main dynamic aspect of the Controller functionality is to support communicating with the few types of
-/// remote peers available (A server, a client with encryption, a client without encryption, etc.). See [Controller]
-/// for more information.
+/// remote peers available (A server, a client with encryption, a client without encryption, etc.). See
+/// [crate::controller::Controller] for more information.
 pub(crate) struct Connection {
-    /// A channel to send commands to the connection's running task.
-    sender: mpsc::Sender<Box<dyn Any + Send>>,
+    /// A channel to send normal priority commands to the connection's running task.
+    sender: mpsc::Sender<Queued>,
+    /// A channel to send high priority commands to the connection's running task.
+    high_priority_sender: mpsc::Sender<Queued>,
+    /// A channel to send bulk commands to the connection's running task.
+    bulk_sender: BulkQueue,
     /// A token to signal to the connection's running task to disconnect from the remote peer and shutdown.
     token: tokio_util::sync::CancellationToken,
     /// The running task's join handle so it is possible to await its termination.
     handle: tokio::task::JoinHandle<()>,
+    /// The throughput counters, updated by the running task.
+    counters: Arc<Counters>,
+    /// Whether the running task reported the connection as established with [Command::Established].
+    established: bool,
 }
 
 impl Connection {
     /// Spawns a task to manage the peer connection.
     ///
     /// The task will run until the connection is terminated, either by the remove peer or by calling
-    /// [Self::disconnect]. This controller ultimetly wakes up and responds to three different events:
+    /// [Self::disconnect]. This controller ultimetly wakes up and responds to five different events:
     ///
     /// 1. The cancellation token is triggered, typically by calling [Self::disconnect]. This will result in the
     ///    connection sending a disconnect message to the manager (so the manager can clean up its state) and then self
     ///    terminating.
     /// 2. A command from the manager is received. This command is processed by the underlying controller's
-    ///    [Controller::process_cmd] method.
-    pub fn spawn<C: Controller>(
-        stream: TcpStream,
+    ///    [crate::controller::Controller::process_cmd] method. High priority commands are processed first, but at
+    ///    most 8 in a row while other commands are waiting, and bulk commands last. A command is only received once
+    ///    the frames of the previous one were written.
+    /// 3. The frames of a command, or the replies to a frame, were written to the remote peer.
+    /// 4. A frame is received from the remote peer. This frame is processed by the underlying controller's
+    ///    [crate::controller::Controller::process_incoming_frame] method. Frames are only received while few replies
+    ///    are waiting to be written.
+    /// 5. If [AmsConfig::idle_timeout] is configured, no frame was received and no command was processed within the
+    ///    timeout. This will result in the connection sending a disconnect message to the manager and then self
+    ///    terminating.
+    ///
+    /// Frames are written while waiting for the other events, so that two peers writing to each other keep reading each
+    /// other's frames. When more than one event is ready, one of them is picked at random. Before handling any of them,
+    /// the task performs the [Handshake] and initializes the controller stack, then reports to the manager whether the
+    /// connection was established or rejected.
+    pub fn spawn(
+        stream: impl Io,
         addr: SocketAddr,
+        side: Side,
+        stack: StackKind,
         manager_tx: mpsc::Sender<Command>,
+        config: &AmsConfig,
     ) -> Self {
-        let (tx, mut rx) = mpsc::channel(32);
+        Self::spawn_with_hooks(stream, addr, side, stack, manager_tx, config, ())
+    }
+
+    /// Spawns a task to manage the peer connection, observing its event loop through the provided [Hooks].
+    fn spawn_with_hooks<H: Hooks>(
+        stream: impl Io,
+        addr: SocketAddr,
+        side: Side,
+        stack: StackKind,
+        manager_tx: mpsc::Sender<Command>,
+        config: &AmsConfig,
+        mut hooks: H,
+    ) -> Self {
+        let (tx, normal) = mpsc::channel(32);
+        let (high_priority_tx, high) = mpsc::channel(32);
+        let (bulk_tx, bulk) = mpsc::channel(BULK_CAPACITY);
+        let mut queues = Queues {
+            high,
+            normal,
+            bulk,
+            streak: 0,
+        };
         let token = tokio_util::sync::CancellationToken::new();
         let cancellation_token = token.clone();
+        let idle_timeout = config.idle_timeout;
+        let handshake_timeout = config.handshake_timeout;
+        let handshake = Handshake::new(side, stack, config);
+        let init = Init::new(side, config);
+        let counters = Arc::new(Counters::new());
+        let task_counters = counters.clone();
 
-        let handle = tokio::spawn(async move {
-            let framed = Framed::new(stream, LengthDelimitedCodec::new());
-            tokio::pin!(framed);
+        let panic_tx = manager_tx.clone();
+        // Whether the manager was told the connection is established, deciding how a panic of the task is reported.
+        let established = Arc::new(AtomicBool::new(false));
+        let task_established = established.clone();
+        let task = async move {
+            // A remote peer that stalls the handshake would otherwise hold the connection open forever.
+            let establish = tokio::time::timeout(handshake_timeout, async {
+                let mut framed = handshake.perform(stream, addr).await.map_err(|e| e.to_string())?;
+                let layers = stack.initialize(&mut framed, &init).await?;
+                let nickname = layers.nickname().map(str::to_string);
+                Ok::<_, String>((framed, layers, nickname))
+            });
+            let (framed, mut layers, nickname) = tokio::select! {
+                // The manager has signaled for this connection to shutdown before it was fully established. Nothing
+                // queued can be sent, so report the queued messages as failed.
+                _ = cancellation_token.cancelled() => {
+                    fail_queued(&mut queues, &manager_tx, addr);
+                    return;
+                }
+                result = establish => match result.unwrap_or_else(|_| Err("timed out establishing the connection".to_string())) {
+                    Ok(established) => established,
+                    Err(err) => {
+                        tracing::info!(%err, "failed to establish connection");
+                        fail_queued(&mut queues, &manager_tx, addr);
+                        let _ = manager_tx.send(Command::Rejected { addr }).await;
+                        return;
+                    }
+                },
+            };
+            let _ = manager_tx.send(Command::Established { addr, nickname }).await;
+            task_established.store(true, Ordering::Relaxed);
+            // Frames are written while reading, so two peers writing to each other cannot block each other.
+            let (mut sink, mut stream) = futures_util::StreamExt::split(framed);
+            let mut outgoing = Outgoing::default();
 
-            let mut layers = C::initialize(&mut framed).await;
+            // Only polled when an idle timeout is configured, and reset whenever the connection is active.
+            let idle = tokio::time::sleep(idle_timeout.unwrap_or_default());
+            tokio::pin!(idle);
 
             loop {
+                hooks.before_wakeup().await;
                 tokio::select! {
                     // The manager has signaled for this connection to shutdown.
                     _ = cancellation_token.cancelled() => {
+                        hooks.after_wakeup(Step::Cancelled);
+                        // Flush the frames and commands queued before the disconnect so their messages are not
+                        // silently lost. The manager may be waiting on this task to finish, so the results must not
+                        // wait on it, and the writes must not wait indefinitely on a remote peer that stopped reading.
+                        queues.close();
+                        while let Some(Queued { report, cmd }) = queues.try_recv() {
+                            match process_cmd(&mut layers, cmd) {
+                                Ok(frames) => outgoing.push(frames, report),
+                                Err(reason) => if let Some(report) = report {
+                                    let _ = manager_tx.try_send(report.failed(addr, reason));
+                                }
+                            }
+                        }
+                        // Tell the remote peer the disconnect is deliberate, if the stack includes a Goodbye layer.
+                        if let Ok(goodbye) = process_cmd(&mut layers, Box::new(crate::layers::goodbye::Cmd::Goodbye)) {
+                            outgoing.push(goodbye, None);
+                        }
+                        let _ = tokio::time::timeout(FLUSH_TIMEOUT, async {
+                            while !outgoing.is_empty() {
+                                match std::future::poll_fn(|cx| outgoing.poll_write(cx, &mut sink, &task_counters)).await {
+                                    Ok(report) => if let Some(sent) = report.and_then(|report| report.sent(addr)) {
+                                        let _ = manager_tx.try_send(sent);
+                                    }
+                                    Err(_) => break,
+                                }
+                            }
+                        }).await;
+                        for report in outgoing.clear() {
+                            let _ = manager_tx.try_send(report.failed(addr, FailureReason::Disconnected));
+                        }
                         break;
                     }
-                    // A command from the manager was sent. Process it through the controller layers.
-                    Some(cmd) = rx.recv() => {
-                        if let Some(bytes) = layers.process_cmd(cmd) {
-                            if framed.send(bytes.freeze()).await.is_err() {
-                                let _ = manager_tx.send(Command::Disconnect{ addr }).await;
+                    // The frames of a command or reply were written to the remote peer.
+                    written = std::future::poll_fn(|cx| outgoing.poll_write(cx, &mut sink, &task_counters)), if !outgoing.is_empty() => {
+                        hooks.after_wakeup(Step::Written);
+                        match written {
+                            Ok(report) => if let Some(sent) = report.and_then(|report| report.sent(addr)) {
+                                let _ = manager_tx.send(sent).await;
+                            }
+                            Err(err) => {
+                                tracing::debug!(%err, "failed to write frame");
+                                // Report the unwritten messages before the disconnect so the sender can retry them.
+                                for report in outgoing.clear() {
+                                    let _ = manager_tx.send(report.failed(addr, FailureReason::Disconnected)).await;
+                                }
+                                let _ = manager_tx.send(Command::Disconnect{ addr, reason: DisconnectReason::Error }).await;
                                 break;
                             }
                         }
                     }
-                    // An incoming frame from the remote peer.
-                    maybe_frame = framed.next() => {
+                    // A command from the manager was sent. Process it through the controller layers once the frames of
+                    // the previous one were written, so commands queue up in their priority queues rather than here.
+                    Some(Queued { report, cmd }) = queues.recv(), if outgoing.is_empty() => {
+                        hooks.after_wakeup(Step::Command);
+                        if let Some(timeout) = idle_timeout {
+                            idle.as_mut().reset(Instant::now() + timeout);
+                        }
+                        match process_cmd(&mut layers, cmd) {
+                            Ok(frames) => outgoing.push(frames, report),
+                            Err(reason) => {
+                                tracing::debug!(?reason, "command produced no frames");
+                                if let Some(report) = report {
+                                    let _ = manager_tx.send(report.failed(addr, reason)).await;
+                                }
+                            }
+                        }
+                    }
+                    // An incoming frame from the remote peer. Frames are only read while few replies are waiting to be
+                    // written, so a remote peer that stopped reading cannot grow them without bound.
+                    maybe_frame = stream.next(), if outgoing.replies < MAX_PENDING_REPLIES => {
+                        hooks.after_wakeup(Step::Frame);
                         match maybe_frame {
                             // Successfully received a frame. Process it through the controller layers.
                             Some(Ok(mut frame)) => {
-                                for cmd in layers.process_incoming_frame(&mut frame) {
-                                    let _ = manager_tx.send(cmd).await;
+                                tracing::debug!(bytes = frame.len(), "received frame");
+                                task_counters.received(frame.len());
+                                if let Some(timeout) = idle_timeout {
+                                    idle.as_mut().reset(Instant::now() + timeout);
+                                }
+                                match layers.process_incoming_frame(&mut frame) {
+                                    Ok(Processed { signals, replies }) => {
+                                        // A layer replied to the frame. Send the reply back to the remote peer.
+                                        if !replies.is_empty() {
+                                            outgoing.push_replies(replies);
+                                        }
+                                        let mut disconnect = None;
+                                        for signal in signals {
+                                            match signal {
+                                                Signal::Disconnect(reason) => disconnect = Some(reason),
+                                                Signal::Relay { recipient, message } => {
+                                                    let _ = manager_tx.send(Command::Relay { addr, recipient, message }).await;
+                                                }
+                                                Signal::FramesLost { count } => {
+                                                    let _ = manager_tx.send(Command::FramesLost { addr, count }).await;
+                                                }
+                                                Signal::Message(message) => {
+                                                    let _ = manager_tx.send(Command::MessageReceived { addr, message }).await;
+                                                }
+                                                Signal::Presence(presence) => {
+                                                    let _ = manager_tx.send(Command::PresenceUpdate { addr, presence }).await;
+                                                }
+                                                Signal::Typing => {
+                                                    let _ = manager_tx.send(Command::PeerTyping { addr }).await;
+                                                }
+                                                Signal::RoomMembership { room, joined } => {
+                                                    let _ = manager_tx.send(Command::RoomMembership { addr, room, joined }).await;
+                                                }
+                                                Signal::RoomMessage(message) => {
+                                                    let _ = manager_tx.send(Command::RoomMessage { addr, message }).await;
+                                                }
+                                                Signal::Reaction(reaction) => {
+                                                    let _ = manager_tx.send(Command::PeerReacted { addr, reaction }).await;
+                                                }
+                                                Signal::Transfer(transfer) => {
+                                                    let _ = manager_tx.send(Command::Transfer { addr, transfer }).await;
+                                                }
+                                            }
+                                        }
+                                        // A layer requested a disconnect. Notify the manager to clean up state.
+                                        if let Some(reason) = disconnect {
+                                            let _ = manager_tx.send(Command::Disconnect { addr, reason }).await;
+                                            break;
+                                        }
+                                    }
+                                    // A layer discarded the frame. Let the manager report why.
+                                    Err(Dropped { layer, reason }) => {
+                                        tracing::debug!(layer, %reason, "frame dropped");
+                                        let _ = manager_tx.send(Command::LayerDropped { addr, layer, reason }).await;
+                                    }
                                 }
                             }
                             // Some error (or disconnect) occured. Notify the manager to clean up state and send a final
                             // disconnect message to this task.
-                            Some(Err(_)) | None => {
-                                let _ = manager_tx.send(Command::Disconnect{ addr }).await;
+                            Some(Err(err)) => {
+                                tracing::debug!(%err, "failed to read frame");
+                                let _ = manager_tx.send(Command::Disconnect{ addr, reason: DisconnectReason::Error }).await;
+                                break;
+                            }
+                            None => {
+                                let _ = manager_tx.send(Command::Disconnect{ addr, reason: DisconnectReason::Remote }).await;
                                 break;
                             }
                         }
                     }
+                    // The connection has been idle for too long. Notify the manager to clean up state.
+                    () = &mut idle, if idle_timeout.is_some() => {
+                        hooks.after_wakeup(Step::Idle);
+                        let _ = manager_tx.send(Command::Disconnect{ addr, reason: DisconnectReason::Timeout }).await;
+                        break;
+                    }
                 }
             }
+        }.instrument(tracing::info_span!("connection", peer = %addr));
+        let handle = config.spawn(async move {
+            // A panic, e.g. in a layer, ends the task without it notifying the manager. Notify it on the task's behalf.
+            if AssertUnwindSafe(task).catch_unwind().await.is_err() {
+                tracing::error!(peer = %addr, "connection task panicked");
+                // A connection that was never reported as established is rejected rather than disconnected.
+                let command = if established.load(Ordering::Relaxed) {
+                    Command::Disconnect {
+                        addr,
+                        reason: DisconnectReason::Panicked,
+                    }
+                } else {
+                    Command::Rejected { addr }
+                };
+                let _ = panic_tx.send(command).await;
+            }
         });
 
         Self {
             sender: tx,
+            high_priority_sender: high_priority_tx,
+            bulk_sender: BulkQueue(bulk_tx),
             token,
             handle,
+            counters,
+            established: false,
         }
     }
 
-    /// Sends a command to the underlying connection controller.
-    pub async fn send_command(&self, command: Box<dyn Any + Send>) {
-        let _ = self.sender.send(command).await;
+    /// Sends a command to the underlying connection controller, queued with the specified priority.
+    pub async fn send_command(&self, command: Box<dyn Any + Send>, priority: Priority) {
+        let sender = match priority {
+            Priority::High => &self.high_priority_sender,
+            Priority::Normal => &self.sender,
+        };
+        let _ = sender
+            .send(Queued {
+                report: None,
+                cmd: command,
+            })
+            .await;
+    }
+
+    /// Sends a command carrying the message with the specified id to the underlying connection controller, queued with
+    /// normal priority.
+    ///
+    /// Once the resulting frame is written to (or fails to write to) the remote peer, a [Command::MessageSent] (or
+    /// [Command::MessageFailed]) is sent to the manager for the message.
+    pub async fn send_message(&self, message_id: u64, command: Box<dyn Any + Send>) {
+        let _ = self
+            .sender
+            .send(Queued {
+                report: Some(Report::Message(message_id)),
+                cmd: command,
+            })
+            .await;
+    }
+
+    /// Returns the queue of bulk commands for the connection, which remains usable independently of this handle.
+    pub fn bulk_queue(&self) -> BulkQueue {
+        self.bulk_sender.clone()
     }
 
-    /// Gracefully disconnects the connection.
-    pub async fn disconnect(self) {
+    /// Returns a snapshot of the connection's throughput counters.
+    pub fn stats(&self) -> ConnectionStats {
+        self.counters.snapshot()
+    }
+
+    /// Returns whether the connection was established, rather than still performing the handshake or initializing its
+    /// controller stack.
+    pub fn is_established(&self) -> bool {
+        self.established
+    }
+
+    /// Records that the running task reported the connection as established.
+    pub fn set_established(&mut self) {
+        self.established = true;
+    }
+
+    /// Gracefully disconnects the connection, flushing any commands already queued for it.
+    pub async fn disconnect(mut self) {
         self.token.cancel();
-        let _ = self.handle.await;
+        let _ = (&mut self.handle).await;
+    }
+}
+
+impl Drop for Connection {
+    /// Aborts the running task if the connection is dropped without being disconnected, e.g. when the manager is
+    /// forcibly shut down.
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// The event that woke up a connection's running task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// The cancellation token was triggered.
+    Cancelled,
+    /// A command from the manager was received.
+    Command,
+    /// The frames of a command or reply were written to the remote peer.
+    Written,
+    /// A frame (or an error / end of stream) was received from the remote peer.
+    Frame,
+    /// The idle timeout elapsed.
+    Idle,
+}
+
+/// Observation points in a connection's running task, used to drive the task deterministically in tests.
+///
+/// The unit type implements this trait with no-ops, and is what [Connection::spawn] uses.
+trait Hooks: Send + 'static {
+    /// Called before the task waits for its next event.
+    fn before_wakeup(&mut self) -> impl std::future::Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called with the event that woke up the task, before it is handled.
+    fn after_wakeup(&mut self, _step: Step) {}
+}
+
+impl Hooks for () {}
+
+/// The priority a command is queued with for a connection's running task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// Control traffic, e.g. pings and typing notifications, processed ahead of normal priority commands.
+    High,
+    /// Messages and other bulk traffic.
+    Normal,
+}
+
+/// A command queued for a connection's running task.
+struct Queued {
+    /// What to report to the manager once the command is processed, if anything.
+    report: Option<Report>,
+    /// The command to process through the controller layers.
+    cmd: Box<dyn Any + Send>,
+}
+
+/// What a connection's running task reports to the manager once a queued command is processed.
+#[derive(Debug, Clone, Copy)]
+enum Report {
+    /// The command carries the message with the specified id, reported once it was written or failed to be.
+    Message(u64),
+    /// The command carries a part of the file transfer with the specified id, reported if it failed to be written.
+    Transfer(u64),
+}
+
+impl Report {
+    /// Returns the command reporting to the manager that the command was written to the remote peer, if any.
+    fn sent(self, addr: SocketAddr) -> Option<Command> {
+        match self {
+            Self::Message(message_id) => Some(Command::MessageSent { addr, message_id }),
+            Self::Transfer(_) => None,
+        }
+    }
+
+    /// Returns the command reporting to the manager that the command could not be written to the remote peer.
+    fn failed(self, addr: SocketAddr, reason: FailureReason) -> Command {
+        match self {
+            Self::Message(message_id) => Command::MessageFailed {
+                addr,
+                message_id,
+                reason,
+            },
+            Self::Transfer(transfer_id) => Command::TransferAborted { addr, transfer_id },
+        }
+    }
+}
+
+/// The command queues of a connection's running task, from the highest priority to the lowest.
+struct Queues {
+    /// The high priority commands.
+    high: mpsc::Receiver<Queued>,
+    /// The normal priority commands.
+    normal: mpsc::Receiver<Queued>,
+    /// The bulk commands, see [BulkQueue].
+    bulk: mpsc::Receiver<Queued>,
+    /// The number of high priority commands received since the last command of a lower priority.
+    streak: usize,
+}
+
+impl Queues {
+    /// Receives the next queued command, preferring high priority commands unless [MAX_HIGH_PRIORITY_STREAK] of them
+    /// were received in a row while commands of a lower priority are waiting.
+    ///
+    /// Returns `None` once every queue is closed and empty. Like [mpsc::Receiver::recv], this is cancel safe.
+    async fn recv(&mut self) -> Option<Queued> {
+        if self.streak >= MAX_HIGH_PRIORITY_STREAK
+            && let Ok(queued) = self.normal.try_recv().or_else(|_| self.bulk.try_recv())
+        {
+            self.streak = 0;
+            return Some(queued);
+        }
+        tokio::select! {
+            biased;
+            Some(queued) = self.high.recv() => {
+                self.streak += 1;
+                Some(queued)
+            }
+            Some(queued) = self.normal.recv() => {
+                self.streak = 0;
+                Some(queued)
+            }
+            Some(queued) = self.bulk.recv() => {
+                self.streak = 0;
+                Some(queued)
+            }
+            else => None,
+        }
+    }
+
+    /// Receives a command already queued, in priority order, without waiting.
+    fn try_recv(&mut self) -> Option<Queued> {
+        self.high
+            .try_recv()
+            .or_else(|_| self.normal.try_recv())
+            .or_else(|_| self.bulk.try_recv())
+            .ok()
+    }
+
+    /// Closes every queue, so that no more commands can be queued.
+    fn close(&mut self) {
+        self.high.close();
+        self.normal.close();
+        self.bulk.close();
+    }
+}
+
+/// A frame waiting to be written to the remote peer, or the point at which the frames before it must be flushed.
+enum Pending {
+    /// A frame to write.
+    Frame {
+        /// The frame.
+        frame: BytesMut,
+        /// Whether the frame is a reply to an incoming frame.
+        reply: bool,
+    },
+    /// Flushes the frames written so far, completing the command with the specified report, if any.
+    Flush(Option<Report>),
+}
+
+/// The frames waiting to be written to the remote peer by a connection's running task, in the order they were produced.
+#[derive(Default)]
+struct Outgoing {
+    /// The frames, each command's or reply's followed by a flush.
+    queue: VecDeque<Pending>,
+    /// The number of replies to incoming frames in the queue.
+    replies: usize,
+}
+
+impl Outgoing {
+    /// Returns whether nothing is waiting to be written.
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queues the frames of a command, with what to report once they are written, if anything.
+    fn push(&mut self, frames: Vec<BytesMut>, report: Option<Report>) {
+        self.queue
+            .extend(frames.into_iter().map(|frame| Pending::Frame {
+                frame,
+                reply: false,
+            }));
+        self.queue.push_back(Pending::Flush(report));
+    }
+
+    /// Queues the replies to an incoming frame.
+    fn push_replies(&mut self, frames: Vec<BytesMut>) {
+        self.replies += frames.len();
+        self.queue.extend(
+            frames
+                .into_iter()
+                .map(|frame| Pending::Frame { frame, reply: true }),
+        );
+        self.queue.push_back(Pending::Flush(None));
+    }
+
+    /// Writes the queued frames to the sink until the next flush, returning the report of the command it completes, if
+    /// any.
+    ///
+    /// Frames are removed from the queue as they are written, so this is cancel safe.
+    fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        sink: &mut SplitSink<Transport, Bytes>,
+        counters: &Counters,
+    ) -> Poll<std::io::Result<Option<Report>>> {
+        loop {
+            match self.queue.front() {
+                Some(Pending::Frame { .. }) => {
+                    ready!(sink.poll_ready_unpin(cx))?;
+                    if let Some(Pending::Frame { frame, reply }) = self.queue.pop_front() {
+                        let len = frame.len();
+                        tracing::debug!(bytes = len, "sending frame");
+                        self.replies -= usize::from(reply);
+                        sink.start_send_unpin(frame.freeze())?;
+                        counters.sent(len);
+                    }
+                }
+                Some(Pending::Flush(report)) => {
+                    let report = *report;
+                    ready!(sink.poll_flush_unpin(cx))?;
+                    self.queue.pop_front();
+                    return Poll::Ready(Ok(report));
+                }
+                None => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+
+    /// Discards the queued frames, returning the reports of the commands that were not completely written.
+    fn clear(&mut self) -> impl Iterator<Item = Report> + '_ {
+        self.replies = 0;
+        self.queue.drain(..).filter_map(|pending| match pending {
+            Pending::Flush(report) => report,
+            Pending::Frame { .. } => None,
+        })
+    }
+}
+
+/// A queue of bulk commands for a connection's running task, e.g. the chunks of a file transfer.
+///
+/// Bulk commands are only processed once no other command is waiting, and few of them are queued at once, so that
+/// bulk traffic is paced by the connection rather than delaying messages and control traffic.
+#[derive(Clone)]
+pub(crate) struct BulkQueue(mpsc::Sender<Queued>);
+
+impl BulkQueue {
+    /// Queues the command carrying a part of the file transfer with the specified id, waiting for room in the queue.
+    /// Returns whether it was queued, i.e. the connection is still running.
+    ///
+    /// If the command cannot be written to the remote peer, a [Command::TransferAborted] is sent to the manager for the
+    /// transfer.
+    pub async fn send(&self, transfer_id: u64, command: Box<dyn Any + Send>) -> bool {
+        self.0
+            .send(Queued {
+                report: Some(Report::Transfer(transfer_id)),
+                cmd: command,
+            })
+            .await
+            .is_ok()
+    }
+}
+
+/// Processes a command through the controller layers, returning its frames, or the reason it produced none.
+fn process_cmd(
+    layers: &mut Box<dyn DynController>,
+    cmd: Box<dyn Any + Send>,
+) -> Result<Vec<BytesMut>, FailureReason> {
+    match layers.process_cmd(cmd) {
+        // The layer that handled the command could not encode it.
+        Ok(frames) if frames.is_empty() => Err(FailureReason::Unhandled),
+        result => result,
+    }
+}
+
+/// Reports the messages and transfers queued for a connection that will never be established as failed, closing the
+/// queues.
+fn fail_queued(queues: &mut Queues, manager_tx: &mpsc::Sender<Command>, addr: SocketAddr) {
+    queues.close();
+    while let Some(Queued { report, .. }) = queues.try_recv() {
+        if let Some(report) = report {
+            let _ = manager_tx.try_send(report.failed(addr, FailureReason::Disconnected));
+        }
     }
 }
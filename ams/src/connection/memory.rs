@@ -0,0 +1,139 @@
+//! A test-mode transport connecting two connections in memory.
+//!
+//! [Connection::spawn_pair] connects two connection tasks through a [tokio::io::duplex] stream rather than a socket.
+//! This allows the path of a command through one connection to the resulting command on the other side to be tested
+//! without binding listeners or contending for ports.
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc;
+
+use super::Connection;
+use crate::{AmsConfig, Command, controller::StackKind, transport::Side};
+
+/// The number of bytes buffered in each direction of the in-memory stream.
+const BUFFER_LEN: usize = 64 * 1024;
+
+/// One end of an in-memory connection: the address the peer at the other end sees, and the manager channel the
+/// connection reports to.
+pub(crate) type Peer = (SocketAddr, mpsc::Sender<Command>);
+
+impl Connection {
+    /// Spawns two connection tasks connected to each other in memory, both using the same stack and configuration.
+    ///
+    /// The first connection acts as if it connected to the second peer, and the second as if it accepted a connection
+    /// from the first peer. Each reports to its own manager channel, keyed by the other peer's address.
+    pub fn spawn_pair(
+        outbound: Peer,
+        inbound: Peer,
+        stack: StackKind,
+        config: &AmsConfig,
+    ) -> (Self, Self) {
+        let (outbound_stream, inbound_stream) = tokio::io::duplex(BUFFER_LEN);
+        let (outbound_addr, outbound_tx) = outbound;
+        let (inbound_addr, inbound_tx) = inbound;
+        (
+            Self::spawn(
+                outbound_stream,
+                inbound_addr,
+                Side::Outbound,
+                stack,
+                outbound_tx,
+                config,
+            ),
+            Self::spawn(
+                inbound_stream,
+                outbound_addr,
+                Side::Inbound,
+                stack,
+                inbound_tx,
+                config,
+            ),
+        )
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::{api::Message, layers::transmit};
+
+    #[tokio::test]
+    async fn message_sent_in_memory_is_received_by_the_peer() {
+        let outbound_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let inbound_addr: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(8);
+        let (inbound_tx, mut inbound_rx) = mpsc::channel(8);
+        let (outbound, _inbound) = Connection::spawn_pair(
+            (outbound_addr, outbound_tx),
+            (inbound_addr, inbound_tx),
+            StackKind::default(),
+            &AmsConfig::default(),
+        );
+        assert!(matches!(
+            outbound_rx.recv().await,
+            Some(Command::Established { addr, .. }) if addr == inbound_addr
+        ));
+        assert!(matches!(
+            inbound_rx.recv().await,
+            Some(Command::Established { addr, .. }) if addr == outbound_addr
+        ));
+
+        let message = Message {
+            id: 1,
+            payload: b"hello".to_vec(),
+            sender: "outbound".to_string(),
+        };
+        outbound
+            .send_message(42, Box::new(transmit::Cmd::SendMessage(message)))
+            .await;
+
+        assert!(matches!(
+            outbound_rx.recv().await,
+            Some(Command::MessageSent { addr, message_id: 42 }) if addr == inbound_addr
+        ));
+        let Some(Command::MessageReceived { addr, message }) = inbound_rx.recv().await else {
+            panic!("the peer did not receive the message");
+        };
+        assert_eq!(addr, outbound_addr);
+        assert_eq!(message.payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn peers_writing_to_each_other_do_not_deadlock() {
+        let outbound_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let inbound_addr: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let (outbound_tx, mut outbound_rx) = mpsc::channel(8);
+        let (inbound_tx, mut inbound_rx) = mpsc::channel(8);
+        let peers = Connection::spawn_pair(
+            (outbound_addr, outbound_tx),
+            (inbound_addr, inbound_tx),
+            StackKind::default(),
+            &AmsConfig::default(),
+        );
+        // Each message is far larger than the in-memory stream buffers.
+        for (id, peer) in [(1, &peers.0), (2, &peers.1)] {
+            let message = Message {
+                id,
+                payload: vec![0; 16 * BUFFER_LEN],
+                sender: "peer".to_string(),
+            };
+            peer.send_message(id, Box::new(transmit::Cmd::SendMessage(message)))
+                .await;
+        }
+
+        for (rx, sent_id, received_id) in [(&mut outbound_rx, 1, 2), (&mut inbound_rx, 2, 1)] {
+            let (mut sent, mut received) = (false, false);
+            while !(sent && received) {
+                let command = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+                    .await
+                    .expect("the peers deadlocked");
+                match command {
+                    Some(Command::MessageSent { message_id, .. }) => sent = message_id == sent_id,
+                    Some(Command::MessageReceived { message, .. }) => {
+                        received = message.id == received_id;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! A test-mode hook for single-stepping a connection's running task.
+//!
+//! A connection spawned with [Connection::spawn_stepped] only waits for its next event once the [StepHandle] allows
+//! it to, and reports which event woke it up. Combined with only making one event ready at a time, this allows
+//! ordering-sensitive behavior to be tested deterministically.
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc;
+
+use super::{Connection, Hooks, Step};
+use crate::{
+    AmsConfig, Command,
+    controller::StackKind,
+    layers::Init,
+    transport::{Io, Side},
+};
+
+/// The connection task's side of the stepping hook.
+struct Stepper {
+    /// Permits for the task to wait for its next event, one per step.
+    permits: mpsc::UnboundedReceiver<()>,
+    /// The events that woke up the task, one per step.
+    steps: mpsc::UnboundedSender<Step>,
+}
+
+impl Hooks for Stepper {
+    async fn before_wakeup(&mut self) {
+        // If the handle is dropped, the task runs freely.
+        let _ = self.permits.recv().await;
+    }
+
+    fn after_wakeup(&mut self, step: Step) {
+        let _ = self.steps.send(step);
+    }
+}
+
+/// A handle to single-step a connection's running task.
+pub(crate) struct StepHandle {
+    /// Permits for the task to wait for its next event, one per step.
+    permits: mpsc::UnboundedSender<()>,
+    /// The events that woke up the task, one per step.
+    steps: mpsc::UnboundedReceiver<Step>,
+}
+
+impl StepHandle {
+    /// Allows the connection task to handle exactly one event, returning which event woke it up.
+    ///
+    /// Returns `None` if the task has terminated.
+    pub async fn step(&mut self) -> Option<Step> {
+        self.permits.send(()).ok()?;
+        self.steps.recv().await
+    }
+}
+
+impl Connection {
+    /// Spawns a task to manage the peer connection, which only handles an event when stepped through the returned
+    /// [StepHandle].
+    pub fn spawn_stepped(
+        stream: impl Io,
+        side: Side,
+        addr: SocketAddr,
+        stack: StackKind,
+        manager_tx: mpsc::Sender<Command>,
+        config: &AmsConfig,
+    ) -> (Self, StepHandle) {
+        let (permits_tx, permits_rx) = mpsc::unbounded_channel();
+        let (steps_tx, steps_rx) = mpsc::unbounded_channel();
+        let stepper = Stepper {
+            permits: permits_rx,
+            steps: steps_tx,
+        };
+        let connection =
+            Self::spawn_with_hooks(stream, addr, side, stack, manager_tx, config, stepper);
+
+        (
+            connection,
+            StepHandle {
+                permits: permits_tx,
+                steps: steps_rx,
+            },
+        )
+    }
+}
+
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{
+        api::Message,
+        connection::Priority,
+        layers::transmit::{self, Cmd::SendMessage},
+    };
+
+    /// Returns a command sending a message with the specified id.
+    fn message(id: u64) -> Box<transmit::Cmd> {
+        Box::new(SendMessage(Message {
+            id,
+            payload: vec![0; 64],
+            sender: "stepped".to_string(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn high_priority_command_is_processed_before_queued_normal_priority_command() {
+        let stepped_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let peer_addr: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let (stepped_stream, peer_stream) = tokio::io::duplex(64 * 1024);
+        let (stepped_tx, mut stepped_rx) = mpsc::channel(8);
+        let (peer_tx, mut peer_rx) = mpsc::channel(8);
+        let config = AmsConfig::default();
+        let (stepped, mut steps) = Connection::spawn_stepped(
+            stepped_stream,
+            Side::Outbound,
+            peer_addr,
+            StackKind::default(),
+            stepped_tx,
+            &config,
+        );
+        let _peer = Connection::spawn(
+            peer_stream,
+            stepped_addr,
+            Side::Inbound,
+            StackKind::default(),
+            peer_tx,
+            &config,
+        );
+        assert!(matches!(
+            stepped_rx.recv().await,
+            Some(Command::Established { .. })
+        ));
+        assert!(matches!(
+            peer_rx.recv().await,
+            Some(Command::Established { .. })
+        ));
+
+        stepped.send_message(2, message(2)).await;
+        stepped.send_command(message(1), Priority::High).await;
+        for _ in 0..2 {
+            assert_eq!(steps.step().await, Some(Step::Command));
+            assert_eq!(steps.step().await, Some(Step::Written));
+        }
+
+        for id in [1, 2] {
+            let Some(Command::MessageReceived { message, .. }) = peer_rx.recv().await else {
+                panic!("the peer did not receive the message");
+            };
+            assert_eq!(message.id, id);
+        }
+    }
+
+    #[tokio::test]
+    async fn next_command_waits_for_the_previous_one_to_be_written() {
+        let stepped_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let peer_addr: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        // Too small to hold a message, so writing one only completes once the peer reads it.
+        let (stepped_stream, peer_stream) = tokio::io::duplex(16);
+        let (stepped_tx, mut stepped_rx) = mpsc::channel(8);
+        let (stepped, mut steps) = Connection::spawn_stepped(
+            stepped_stream,
+            Side::Outbound,
+            peer_addr,
+            StackKind::default(),
+            stepped_tx,
+            &AmsConfig::default(),
+        );
+        let peer = tokio::spawn(async move {
+            let mut framed = crate::transport::Handshake::new(
+                Side::Inbound,
+                StackKind::default(),
+                &AmsConfig::default(),
+            )
+            .perform(peer_stream, stepped_addr)
+            .await
+            .unwrap();
+            let init = Init::new(Side::Inbound, &AmsConfig::default());
+            let _layers = StackKind::default()
+                .initialize(&mut framed, &init)
+                .await
+                .unwrap();
+            framed
+        });
+        assert!(matches!(
+            stepped_rx.recv().await,
+            Some(Command::Established { .. })
+        ));
+        let mut framed = peer.await.unwrap();
+
+        stepped.send_message(1, message(1)).await;
+        stepped.send_message(2, message(2)).await;
+        assert_eq!(steps.step().await, Some(Step::Command));
+        // The next command is not processed while the message is waiting to be written. The task keeps the permit.
+        let step = tokio::time::timeout(std::time::Duration::from_millis(100), steps.step()).await;
+        assert!(step.is_err());
+
+        assert!(framed.next().await.is_some());
+        assert_eq!(steps.steps.recv().await, Some(Step::Written));
+        assert!(matches!(
+            stepped_rx.recv().await,
+            Some(Command::MessageSent { message_id: 1, .. })
+        ));
+        assert_eq!(steps.step().await, Some(Step::Command));
+    }
+}
@@ -1,13 +1,29 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet, VecDeque, hash_map::Entry},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpSocket, TcpStream},
     sync::{mpsc, oneshot},
+    time::Instant,
 };
 
-use crate::{Command, api::Message, connection::Connection, layers::transmit};
+use tokio_stream::{StreamExt, wrappers::TcpListenerStream};
+use tracing::Instrument;
+
+use crate::{
+    AcceptRateLimit, AmsConfig, AmsError, AmsStats, Command, ConnectionStats, FailureReason,
+    InboundMode, Rate, SendError,
+    api::{Message, Presence, Transfer},
+    connection::{Connection, Priority},
+    transport::{Io, Side},
+};
 
-type Unsecure = (transmit::Transmit,);
+#[cfg(test)]
+mod tests;
 
 // The AMS connection manager, responsible for managing all incoming and active connections to remote peers.
 pub(crate) struct ConnectionManager {
@@ -17,6 +33,9 @@ pub(crate) struct ConnectionManager {
     token: tokio_util::sync::CancellationToken,
     /// The running manager task's join handle.
     handle: tokio::task::JoinHandle<()>,
+    /// The addresses the manager accepts connections on.
+    #[cfg(test)]
+    pub(crate) addrs: Vec<SocketAddr>,
 }
 
 impl ConnectionManager {
@@ -26,17 +45,38 @@ impl ConnectionManager {
         let _ = self.handle.await;
     }
 
-    pub(crate) async fn send_command(&self, command: Command) {
-        let _ = self.sender.send(command).await;
+    /// Queues a shutdown of the manager and all connections, aborting any that have not finished within the specified
+    /// duration.
+    pub(crate) async fn shutdown_timeout(mut self, dur: Duration) -> crate::Shutdown {
+        self.token.cancel();
+        match tokio::time::timeout(dur, &mut self.handle).await {
+            Ok(_) => crate::Shutdown::Clean,
+            Err(_) => {
+                // Aborting the manager drops its connections, including those being disconnected, which aborts their
+                // tasks.
+                self.handle.abort();
+                let _ = self.handle.await;
+                crate::Shutdown::Forced
+            }
+        }
     }
 
-    /// Spawns a task to manage all incoming and active connections.
+    /// Sends a command to the manager task, failing if it is no longer running.
+    pub(crate) async fn send_command(&self, command: Command) -> Result<(), crate::SendError> {
+        self.sender
+            .send(command)
+            .await
+            .map_err(|_| crate::SendError::Closed)
+    }
+
+    /// Spawns a task to manage all incoming and active connections, accepting connections on the specified listeners.
     ///
     /// The [Command] enum is used to interact with the manager and its connections.
     pub(crate) async fn spawn(
-        addr: impl ToString,
-        event_tx: mpsc::UnboundedSender<crate::Event>,
-    ) -> std::io::Result<Self> {
+        listen: Listen,
+        event_tx: crate::events::EventSender,
+        config: AmsConfig,
+    ) -> Result<Self, AmsError> {
         // Channel to receive commands for the manager.
         let (tx, mut rx) = mpsc::channel(100);
         let token = tokio_util::sync::CancellationToken::new();
@@ -46,60 +86,549 @@ impl ConnectionManager {
         // Namely, to notify it when they are shutting down, so the manager can clean up its state.
         let exit_tx = tx.clone();
 
-        let listener = TcpListener::bind(addr.to_string()).await?;
+        config.codec.validate().map_err(AmsError::InvalidConfig)?;
+        if let Some(limit) = &config.accept_rate_limit {
+            limit.validate().map_err(AmsError::InvalidConfig)?;
+        }
+        config
+            .fragment_limits
+            .validate()
+            .map_err(AmsError::InvalidConfig)?;
+        if config
+            .throughput_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err(AmsError::InvalidConfig(
+                "throughput interval must not be zero".to_string(),
+            ));
+        }
+        // The path of the Unix domain socket, removed once the manager exits.
+        let mut unix_path: Option<PathBuf> = None;
+        let (my_addrs, mut incoming): (
+            Vec<SocketAddr>,
+            futures::stream::BoxStream<'static, Accepted>,
+        ) = match listen {
+            Listen::Tcp(addrs) => {
+                let listeners = bind_all(addrs, &config).await?;
+                let my_addrs = listeners
+                    .iter()
+                    .map(TcpListener::local_addr)
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                let tcp_config = config.clone();
+                let incoming =
+                    futures::stream::select_all(listeners.into_iter().map(TcpListenerStream::new))
+                        .filter_map(move |stream| {
+                            let stream = stream.ok()?;
+                            let addr = stream.peer_addr().ok()?;
+                            configure_stream(&stream, addr, &tcp_config);
+                            Some(Accepted {
+                                stream: Box::new(stream),
+                                addr,
+                                unix: false,
+                            })
+                        });
+                (my_addrs, Box::pin(incoming))
+            }
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                let listener = tokio::net::UnixListener::bind(&path)?;
+                unix_path = Some(path);
+                let mut next_peer = 0;
+                let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener)
+                    .filter_map(move |stream| {
+                        next_peer += 1;
+                        Some(Accepted {
+                            stream: Box::new(stream.ok()?),
+                            addr: unix_peer_addr(next_peer),
+                            unix: true,
+                        })
+                    });
+                (Vec::new(), Box::pin(incoming))
+            }
+        };
+        // The address identifying the instance to peers, e.g. as the sender of its messages.
+        let my_addr = my_addrs
+            .first()
+            .copied()
+            .unwrap_or_else(|| unix_peer_addr(0));
+        let listening = match &unix_path {
+            Some(path) => path.display().to_string(),
+            None => my_addr.to_string(),
+        };
+        #[cfg(test)]
+        let addrs = my_addrs.clone();
+        // Unix domain socket peers are local, and have no IP address to verify a certificate against.
+        #[cfg(feature = "tls")]
+        let unix_config = AmsConfig {
+            tls: Default::default(),
+            ..config.clone()
+        };
+        #[cfg(not(feature = "tls"))]
+        let unix_config = config.clone();
 
-        let handle = tokio::spawn(async move {
-            let mut connections = HashMap::new();
-            let my_addr = listener.local_addr().unwrap();
+        let handle = config.clone().spawn(async move {
+            let started = Instant::now();
+            let mut connections: HashMap<SocketAddr, Connection> = HashMap::new();
+            // The presence announced to peers, once set.
+            let mut presence = None;
+            // The nickname each established connection introduced itself with, if unique.
+            let mut nicknames: HashMap<SocketAddr, String> = HashMap::new();
+            // The rooms joined, announced to peers.
+            let mut rooms = HashSet::new();
+            // The peers that joined each room.
+            let mut members: HashMap<String, HashSet<SocketAddr>> = HashMap::new();
+            // The callers of Ams::send_message_confirmed waiting for the outcome of their message, keyed by id.
+            let mut confirmations: HashMap<u64, oneshot::Sender<Result<SystemTime, SendError>>> = HashMap::new();
+            // Forgets the callers that have given up waiting, even if no other confirmed message is sent.
+            let mut sweep = tokio::time::interval_at(Instant::now() + CONFIRMATION_SWEEP, CONFIRMATION_SWEEP);
+            sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // Only ticks if a throughput interval is configured.
+            let window = config.throughput_interval.unwrap_or(Duration::from_secs(1));
+            let mut throughput = tokio::time::interval_at(Instant::now() + window, window);
+            throughput.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // The bytes sent to and received from each peer as of the previous tick.
+            let mut last_counts: HashMap<SocketAddr, (u64, u64)> = HashMap::new();
+            // The files being sent, keyed by transfer id, until their outcome is known.
+            let mut sending: HashMap<u64, Sending> = HashMap::new();
+            // Only set if an accept rate limit is configured.
+            let mut limiter = config.accept_rate_limit.map(AcceptLimiter::new);
+            // The messages recently received from each peer, to suppress duplicates.
+            let mut seen: HashMap<SocketAddr, Seen> = HashMap::new();
+            // The parts of each file being received, forwarded to the task writing it.
+            let mut receiving: HashMap<(SocketAddr, u64), mpsc::Sender<Transfer>> = HashMap::new();
+            // The connections being disconnected, each flushing its queued commands without holding up the manager.
+            let mut closing = tokio::task::JoinSet::new();
+            // The number of outbound connections to Unix domain sockets, identifying each with a unique address.
+            #[cfg(unix)]
+            let mut unix_peers = 0;
 
             loop {
                 tokio::select! {
                     _ = cancellation_token.cancelled() => {
                         break;
                     }
-                    // Handle a new connection
-                    Ok((stream, addr)) = listener.accept() => {
-                        let (rx, tx) = oneshot::channel();
-                        if event_tx.send(crate::Event::ConnectionRequested { peer: addr, response: rx }).is_err() {
+                    // Handle a new connection from any of the listeners
+                    Some(Accepted { stream, addr, unix }) = incoming.next() => {
+                        // Unix domain socket peers have no IP address, access to the socket being controlled by its
+                        // file permissions instead.
+                        let ip = (!unix).then(|| addr.ip());
+                        if let Some(limiter) = &mut limiter && let Some(ip) = ip && !limiter.permits(ip) {
+                            continue;
+                        }
+                        // A connection replacing another to the same address would abort it without reporting it.
+                        // Connections still being established do not count toward the maximum, but are bounded on their own.
+                        let established = connections.values().filter(|conn| conn.is_established()).count();
+                        let pending = connections.len() - established;
+                        if connections.contains_key(&addr) || is_self(addr, &my_addrs) || pending >= MAX_PENDING_CONNECTIONS || !config.accept_policy.permits(ip, established) {
+                            tracing::info!(peer = %addr, "rejected incoming connection");
+                            event_tx.send(crate::Event::ConnectionRejected { peer: addr });
                             continue;
                         }
-                        if let Ok(true) = tx.await {
-                            let conn = Connection::spawn::<Unsecure>(stream, addr, exit_tx.clone());
+                        let accepted = match config.inbound_mode {
+                            InboundMode::Prompt => {
+                                let (rx, tx) = oneshot::channel();
+                                if !event_tx.send(crate::Event::ConnectionRequested { peer: addr, response: rx }) {
+                                    continue;
+                                }
+                                matches!(tx.await, Ok(true))
+                            }
+                            InboundMode::AcceptAll => true,
+                            InboundMode::RejectAll => {
+                                tracing::info!(peer = %addr, "rejected incoming connection");
+                                event_tx.send(crate::Event::ConnectionRejected { peer: addr });
+                                false
+                            }
+                        };
+                        if accepted {
+                            let conn_config = if unix { &unix_config } else { &config };
+                            let conn = Connection::spawn(stream, addr, Side::Inbound, config.stack, exit_tx.clone(), conn_config);
+                            announce(&conn, &presence, &rooms).await;
                             connections.insert(addr, conn);
-                            let _ = event_tx.send(crate::Event::ConnectionEstablished { peer: addr });
+                            tracing::info!(peer = %addr, "accepted incoming connection");
+                        }
+                    }
+                    // A connection finished disconnecting.
+                    Some(_) = closing.join_next() => {}
+                    // Report the throughput of each connection since the previous tick.
+                    _ = throughput.tick(), if config.throughput_interval.is_some() => {
+                        last_counts.retain(|addr, _| connections.contains_key(addr));
+                        for (addr, conn) in connections.iter().filter(|(_, conn)| conn.is_established()) {
+                            let stats = conn.stats();
+                            let (sent, received) = last_counts
+                                .insert(*addr, (stats.bytes_sent, stats.bytes_received))
+                                .unwrap_or_default();
+                            event_tx.send(crate::Event::Throughput {
+                                peer: *addr,
+                                // A new connection to the same address starts counting from zero again.
+                                bytes_in: stats.bytes_received.saturating_sub(received),
+                                bytes_out: stats.bytes_sent.saturating_sub(sent),
+                                window,
+                            });
                         }
                     }
+                    // Forget the callers that have given up waiting.
+                    _ = sweep.tick(), if !confirmations.is_empty() => {
+                        confirmations.retain(|_, confirm| !confirm.is_closed());
+                    }
                     // Handle a manager command
                     Some(cmd) = rx.recv() => {
                         match cmd {
-                            Command::Disconnect { addr } => {
-                                println!("Disconnecting from {addr}");
+                            Command::Disconnect { addr, reason } => {
+                                tracing::info!(peer = %addr, ?reason, "disconnected");
                                 if let Some(connection) = connections.remove(&addr) {
-                                    connection.disconnect().await;
+                                    closing.spawn(connection.disconnect());
+                                }
+                                nicknames.remove(&addr);
+                                seen.remove(&addr);
+                                last_counts.remove(&addr);
+                                members.retain(|_, peers| {
+                                    peers.remove(&addr);
+                                    !peers.is_empty()
+                                });
+                                sending.retain(|transfer_id, sending| {
+                                    if sending.addr == addr {
+                                        event_tx.send(crate::Event::TransferFailed { peer: addr, transfer_id: *transfer_id });
+                                    }
+                                    sending.addr != addr
+                                });
+                                // The tasks writing the files being received remove them once their parts stop.
+                                receiving.retain(|(peer, _), _| *peer != addr);
+                                event_tx.send(crate::Event::ConnectionDisconnected { peer: addr, reason });
+                            }
+                            Command::Established { addr, nickname } => {
+                                // The connection may have been disconnected while it was being established.
+                                if let Some(conn) = connections.get_mut(&addr) {
+                                    conn.set_established();
+                                    // A nickname already in use cannot tell the two peers apart.
+                                    let nickname = nickname.filter(|nickname| !nicknames.values().any(|used| used == nickname));
+                                    if let Some(nickname) = &nickname {
+                                        nicknames.insert(addr, nickname.clone());
+                                    }
+                                    tracing::info!(peer = %addr, ?nickname, "connection established");
+                                    event_tx.send(crate::Event::ConnectionEstablished { peer: addr, nickname });
                                 }
-                                event_tx.send(crate::Event::ConnectionDisconnected { peer: addr }).ok();
                             }
-                            Command::Connect { addr } => {
-                                if let Ok(stream) = TcpStream::connect(&addr).await {
-                                    let conn = Connection::spawn::<Unsecure>(stream, addr, exit_tx.clone());
+                            Command::Rejected { addr } => {
+                                tracing::info!(peer = %addr, "connection rejected");
+                                if let Some(connection) = connections.remove(&addr) {
+                                    closing.spawn(connection.disconnect());
+                                }
+                                last_counts.remove(&addr);
+                                event_tx.send(crate::Event::ConnectionRejected { peer: addr });
+                            }
+                            Command::Connect { addr, stack } => {
+                                tracing::info!(peer = %addr, "connecting");
+                                let connected = connections.contains_key(&addr);
+                                // Dialing ourselves would create a loopback connection to our own listener.
+                                if is_self(addr, &my_addrs) {
+                                    tracing::info!(peer = %addr, "refused to connect to self");
+                                    event_tx.send(crate::Event::ConnectionRejected { peer: addr });
+                                }
+                                // A connection replacing another to the same address would abort it without reporting it.
+                                else if connected {
+                                    tracing::info!(peer = %addr, "refused to connect to a connected peer");
+                                    event_tx.send(crate::Event::ConnectionRejected { peer: addr });
+                                }
+                                else if let Ok(Ok(stream)) = tokio::time::timeout(config.connect_timeout, TcpStream::connect(&addr)).await {
+                                    configure_stream(&stream, addr, &config);
+                                    let conn = Connection::spawn(stream, addr, Side::Outbound, stack, exit_tx.clone(), &config);
+                                    announce(&conn, &presence, &rooms).await;
+                                    connections.insert(addr, conn);
+                                }
+                                else {
+                                    tracing::info!(peer = %addr, "failed to connect");
+                                    event_tx.send(crate::Event::ConnectionRejected { peer: addr });
+                                }
+                            }
+                            #[cfg(unix)]
+                            Command::ConnectUnix { path, stack, resp } => {
+                                unix_peers += 1;
+                                // Outbound peers are numbered from the other end of the range than inbound ones.
+                                let addr = unix_peer_addr(u64::MAX - unix_peers);
+                                let _ = resp.send(addr);
+                                tracing::info!(peer = %addr, path = %path.display(), "connecting");
+                                // Dialing ourselves would create a loopback connection to our own listener.
+                                if unix_path.as_ref() == Some(&path) {
+                                    tracing::info!(peer = %addr, "refused to connect to self");
+                                    event_tx.send(crate::Event::ConnectionRejected { peer: addr });
+                                }
+                                else if let Ok(Ok(stream)) = tokio::time::timeout(config.connect_timeout, tokio::net::UnixStream::connect(&path)).await {
+                                    let conn = Connection::spawn(stream, addr, Side::Outbound, stack, exit_tx.clone(), &unix_config);
+                                    announce(&conn, &presence, &rooms).await;
                                     connections.insert(addr, conn);
-                                    let _ = event_tx.send(crate::Event::ConnectionEstablished { peer: addr });
+                                }
+                                else {
+                                    tracing::info!(peer = %addr, "failed to connect");
+                                    event_tx.send(crate::Event::ConnectionRejected { peer: addr });
+                                }
+                            }
+                            Command::SendMessage { message_id, addr, data, confirm } => {
+                                let message = Message {
+                                    id: message_id,
+                                    payload: data,
+                                    sender: my_addr.to_string(),
+                                };
+                                if let Some(conn) = connections.get(&addr) {
+                                    if let Some(confirm) = confirm {
+                                        // Forget the callers that have given up waiting.
+                                        confirmations.retain(|_, confirm| !confirm.is_closed());
+                                        confirmations.insert(message_id, confirm);
+                                    }
+                                    conn.send_message(message_id, Box::new(crate::layers::transmit::Cmd::SendMessage(message))).await;
+                                }
+                                else {
+                                    if let Some(confirm) = confirm {
+                                        let _ = confirm.send(Err(SendError::Failed));
+                                    }
+                                    event_tx.send(crate::Event::MessageFailed { peer: addr, message_id, reason: FailureReason::NotConnected });
                                 }
                             }
-                            Command::SendMessage { message_id, addr, data } => {
+                            Command::SendVia { message_id, server, recipient, data } => {
                                 let message = Message {
                                     id: message_id,
                                     payload: data,
                                     sender: my_addr.to_string(),
                                 };
+                                let relay = crate::api::Server { recipient: recipient.to_string(), message };
+                                if let Some(conn) = connections.get(&server) {
+                                    conn.send_message(message_id, Box::new(crate::layers::server::Cmd::Relay(relay))).await;
+                                }
+                                else {
+                                    event_tx.send(crate::Event::MessageFailed { peer: server, message_id, reason: FailureReason::NotConnected });
+                                }
+                            }
+                            Command::Relay { addr, recipient, message } => {
+                                // Deliver the message as if it was sent directly, keeping its original sender.
+                                match connections.get(&recipient) {
+                                    Some(conn) => conn.send_command(Box::new(crate::layers::transmit::Cmd::SendMessage(message)), Priority::Normal).await,
+                                    None => tracing::debug!(peer = %addr, %recipient, "dropped message for unknown relay recipient"),
+                                }
+                            }
+                            Command::MessageSent { addr, message_id } => {
+                                let timestamp = SystemTime::now();
+                                if let Some(confirm) = confirmations.remove(&message_id) {
+                                    let _ = confirm.send(Ok(timestamp));
+                                }
+                                event_tx.send(crate::Event::MessageSent { peer: addr, message_id, timestamp });
+                            }
+                            Command::MessageFailed { addr, message_id, reason } => {
+                                if let Some(confirm) = confirmations.remove(&message_id) {
+                                    let _ = confirm.send(Err(SendError::Failed));
+                                }
+                                event_tx.send(crate::Event::MessageFailed { peer: addr, message_id, reason });
+                            }
+                            Command::Ping { addr, resp } => {
+                                match connections.get(&addr) {
+                                    Some(conn) => conn.send_command(Box::new(crate::layers::ping::Cmd::Ping(resp)), Priority::High).await,
+                                    None => { let _ = resp.send(None); }
+                                }
+                            }
+                            #[cfg(test)]
+                            Command::PendingConfirmations { resp } => {
+                                let _ = resp.send(confirmations.len());
+                            }
+                            Command::Stats { resp } => {
+                                let per_connection: HashMap<_, _> = connections
+                                    .iter()
+                                    .filter(|(_, conn)| conn.is_established())
+                                    .map(|(addr, conn)| (*addr, conn.stats()))
+                                    .collect();
+                                let mut total = ConnectionStats::default();
+                                for stats in per_connection.values() {
+                                    total += *stats;
+                                }
+                                let _ = resp.send(AmsStats {
+                                    uptime: started.elapsed(),
+                                    connections: per_connection.len(),
+                                    total,
+                                    per_connection,
+                                    events_dropped: event_tx.dropped(),
+                                });
+                            }
+                            Command::IsConnected { addr, resp } => {
+                                let _ = resp.send(connections.get(&addr).is_some_and(Connection::is_established));
+                            }
+                            Command::LayerDropped { addr, layer, reason } => {
+                                event_tx.send(crate::Event::LayerDropped { peer: addr, layer, reason });
+                            }
+                            Command::FramesLost { addr, count } => {
+                                event_tx.send(crate::Event::FramesLost { peer: addr, count });
+                            }
+                            Command::SetStatus { presence: new } => {
+                                presence = Some(new);
+                                for conn in connections.values() {
+                                    announce(conn, &presence, &HashSet::new()).await;
+                                }
+                            }
+                            Command::PresenceUpdate { addr, presence } => {
+                                event_tx.send(crate::Event::PresenceUpdate { peer: addr, nickname: presence.nickname, status: presence.status });
+                            }
+                            Command::Typing { addr } => {
+                                if let Some(conn) = connections.get(&addr) {
+                                    conn.send_command(Box::new(crate::layers::typing::Cmd::Typing), Priority::High).await;
+                                }
+                            }
+                            Command::PeerTyping { addr } => {
+                                event_tx.send(crate::Event::PeerTyping { peer: addr });
+                            }
+                            Command::React { addr, reaction } => {
+                                if let Some(conn) = connections.get(&addr) {
+                                    conn.send_command(Box::new(crate::layers::react::Cmd::React(reaction)), Priority::High).await;
+                                }
+                            }
+                            Command::PeerReacted { addr, reaction } => {
+                                event_tx.send(crate::Event::Reaction { peer: addr, message_id: reaction.message_id, emoji: reaction.emoji });
+                            }
+                            Command::JoinRoom { room } => {
+                                if rooms.insert(room.clone()) {
+                                    for conn in connections.values() {
+                                        conn.send_command(Box::new(crate::layers::room::Cmd::Join(room.clone())), Priority::High).await;
+                                    }
+                                }
+                            }
+                            Command::LeaveRoom { room } => {
+                                if rooms.remove(&room) {
+                                    for conn in connections.values() {
+                                        conn.send_command(Box::new(crate::layers::room::Cmd::Leave(room.clone())), Priority::High).await;
+                                    }
+                                }
+                            }
+                            Command::SendToRoom { message_id, room, data } => {
+                                let peers = members.get(&room).into_iter().flatten();
+                                for conn in peers.filter_map(|addr| connections.get(addr)) {
+                                    let message = Message {
+                                        id: message_id,
+                                        payload: data.clone(),
+                                        sender: my_addr.to_string(),
+                                    };
+                                    let message = crate::api::Room { room: room.clone(), message };
+                                    conn.send_message(message_id, Box::new(crate::layers::room::Cmd::Send(message))).await;
+                                }
+                            }
+                            Command::RoomMembership { addr, room, joined } => {
+                                if joined {
+                                    members.entry(room).or_default().insert(addr);
+                                }
+                                else if let Some(peers) = members.get_mut(&room) {
+                                    peers.remove(&addr);
+                                    if peers.is_empty() {
+                                        members.remove(&room);
+                                    }
+                                }
+                            }
+                            Command::RoomMessage { addr, message } => {
+                                // Messages to rooms left in the meantime are no longer of interest.
+                                if rooms.contains(&message.room) {
+                                    event_tx.send(crate::Event::RoomMessage {
+                                        room: message.room,
+                                        peer: addr,
+                                        message_id: message.message.id,
+                                        payload: message.message.payload,
+                                    });
+                                }
+                            }
+                            Command::SendFile { addr, transfer_id, path } => {
                                 if let Some(conn) = connections.get(&addr) {
-                                    conn.send_command(Box::new(crate::layers::transmit::Cmd::SendMessage(message))).await;
-                                    let _ = event_tx.send(crate::Event::MessageSent { peer: addr, message_id, timestamp: SystemTime::now() });
+                                    let (accepted_tx, accepted_rx) = oneshot::channel();
+                                    sending.insert(transfer_id, Sending { addr, accepted: Some(accepted_tx) });
+                                    let accepted = tokio::time::timeout(config.transfer_timeout, accepted_rx);
+                                    config.spawn(crate::transfer::send(conn.bulk_queue(), path, transfer_id, addr, accepted, event_tx.clone(), exit_tx.clone()));
                                 }
                                 else {
-                                    let _ = event_tx.send(crate::Event::MessageFailed { peer: addr, message_id });
+                                    event_tx.send(crate::Event::TransferFailed { peer: addr, transfer_id });
                                 }
                             }
+                            Command::TransferAborted { addr, transfer_id } => {
+                                if sending.remove(&transfer_id).is_some() {
+                                    event_tx.send(crate::Event::TransferFailed { peer: addr, transfer_id });
+                                }
+                            }
+                            Command::Transfer { addr, transfer: Transfer::Result { id, ok } } => {
+                                // Only the peer a file was sent to may report its outcome.
+                                if sending.get(&id).is_some_and(|sending| sending.addr == addr) {
+                                    sending.remove(&id);
+                                    event_tx.send(if ok {
+                                        crate::Event::TransferComplete { peer: addr, transfer_id: id }
+                                    } else {
+                                        crate::Event::TransferFailed { peer: addr, transfer_id: id }
+                                    });
+                                }
+                            }
+                            Command::Transfer { addr, transfer: Transfer::Accepted { id } } => {
+                                // Only the peer a file was sent to may accept it.
+                                if let Some(sending) = sending.get_mut(&id)
+                                    && sending.addr == addr
+                                    && let Some(accepted) = sending.accepted.take()
+                                {
+                                    let _ = accepted.send(());
+                                }
+                            }
+                            Command::Transfer { addr, transfer: Transfer::Start { id, name, size } } => {
+                                match (receiving.entry((addr, id)), crate::transfer::file_name(&name)) {
+                                    (Entry::Occupied(_), _) => {
+                                        tracing::debug!(peer = %addr, transfer_id = id, "ignored restarted transfer");
+                                    }
+                                    (Entry::Vacant(entry), Some(name)) if size <= config.max_file_size => {
+                                        let (parts_tx, parts_rx) = mpsc::channel(TRANSFER_PARTS);
+                                        // The application answers on the task receiving the file, not holding up the manager.
+                                        let answer = config.prompt_files.then(|| {
+                                            let (response, answer) = oneshot::channel();
+                                            event_tx.send(crate::Event::FileOffered { peer: addr, transfer_id: id, name: name.clone(), size, response });
+                                            tokio::time::timeout(config.transfer_timeout, answer)
+                                        });
+                                        let offer = crate::transfer::Offer { id, name, size };
+                                        config.spawn(crate::transfer::receive(parts_rx, offer, addr, answer, config.transfer_timeout, exit_tx.clone()));
+                                        entry.insert(parts_tx);
+                                    }
+                                    (Entry::Vacant(_), _) => {
+                                        tracing::debug!(peer = %addr, transfer_id = id, %name, size, "refused file");
+                                        if let Some(conn) = connections.get(&addr) {
+                                            conn.send_command(Box::new(crate::layers::file::Cmd::Send(Transfer::Result { id, ok: false })), Priority::High).await;
+                                        }
+                                    }
+                                }
+                            }
+                            Command::Transfer { addr, transfer: part @ (Transfer::Chunk { id, .. } | Transfer::End { id, .. }) } => {
+                                // Parts of a transfer that failed are dropped, its failure was reported already.
+                                if let Some(parts_tx) = receiving.get(&(addr, id)) {
+                                    let end = matches!(part, Transfer::End { .. });
+                                    let result = parts_tx.try_send(part);
+                                    // Rather than holding up the manager, a file received faster than it is written fails.
+                                    if let Err(mpsc::error::TrySendError::Full(_)) = result {
+                                        tracing::info!(peer = %addr, transfer_id = id, "file received faster than it is written");
+                                    }
+                                    if result.is_err() || end {
+                                        receiving.remove(&(addr, id));
+                                    }
+                                }
+                            }
+                            Command::AcceptTransfer { addr, transfer_id } => {
+                                if let Some(conn) = connections.get(&addr) {
+                                    conn.send_command(Box::new(crate::layers::file::Cmd::Send(Transfer::Accepted { id: transfer_id })), Priority::High).await;
+                                }
+                            }
+                            Command::FileReceived { addr, transfer_id, file } => {
+                                receiving.remove(&(addr, transfer_id));
+                                if let Some(conn) = connections.get(&addr) {
+                                    conn.send_command(Box::new(crate::layers::file::Cmd::Send(Transfer::Result { id: transfer_id, ok: file.is_some() })), Priority::High).await;
+                                }
+                                if let Some((path, name)) = file {
+                                    event_tx.send(crate::Event::FileReceived { peer: addr, path, name });
+                                }
+                            }
+                            Command::MessageReceived { addr, message } => {
+                                let window = seen.entry(addr).or_default();
+                                if !window.insert(&message, config.dedup_window) {
+                                    tracing::debug!(peer = %addr, id = message.id, "dropped duplicate message");
+                                    continue;
+                                }
+                                event_tx.send(crate::Event::MessageReceived {
+                                    peer: addr,
+                                    message_id: message.id,
+                                    payload: message.payload,
+                                    timestamp: SystemTime::now(),
+                                });
+                            }
                         }
                     }
                 }
@@ -107,12 +636,294 @@ impl ConnectionManager {
 
             futures::future::join_all(connections.into_values().map(|conn| conn.disconnect()))
                 .await;
-        });
+            closing.join_all().await;
+            if let Some(path) = unix_path {
+                let _ = std::fs::remove_file(path);
+            }
+        }.instrument(tracing::info_span!("manager", addr = %listening)));
 
         Ok(Self {
             sender: tx,
             token,
             handle,
+            #[cfg(test)]
+            addrs,
         })
     }
 }
+
+/// The listeners an instance accepts connections on.
+pub(crate) enum Listen {
+    /// TCP listeners bound to each of the addresses.
+    Tcp(Vec<String>),
+    /// A Unix domain socket listener, created at the path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A connection accepted by one of the listeners.
+struct Accepted {
+    stream: Box<dyn Io>,
+    /// The address of the peer, or the address identifying it if it connected to the Unix domain socket.
+    addr: SocketAddr,
+    /// Whether the peer connected to the Unix domain socket.
+    unix: bool,
+}
+
+/// Returns the address identifying the Unix domain socket peer with the specified number, from the discard-only
+/// `100::/64` prefix so that it never collides with the address of a TCP peer.
+fn unix_peer_addr(number: u64) -> SocketAddr {
+    let ip = Ipv6Addr::from((0x0100_u128 << 112) | u128::from(number));
+    SocketAddr::new(IpAddr::V6(ip), 0)
+}
+
+/// Applies the socket options of the provided configuration to a TCP connection.
+fn configure_stream(stream: &TcpStream, addr: SocketAddr, config: &AmsConfig) {
+    let _ = stream.set_nodelay(config.tcp_nodelay);
+    if let Some(keepalive) = &config.tcp_keepalive
+        && let Err(err) = keepalive.apply(stream)
+    {
+        tracing::debug!(peer = %addr, %err, "failed to enable TCP keepalive");
+    }
+}
+
+/// The number of inbound connections that may be established at once, beyond which new ones are rejected so that peers
+/// stalling their handshake cannot exhaust the instance's resources.
+const MAX_PENDING_CONNECTIONS: usize = 64;
+
+/// How often the callers of [crate::Ams::send_message_confirmed] that have given up waiting are forgotten.
+const CONFIRMATION_SWEEP: Duration = Duration::from_secs(1);
+
+/// The number of parts of a file being received buffered until they are written, beyond which the transfer fails rather
+/// than holding up the manager.
+const TRANSFER_PARTS: usize = 64;
+
+/// A file being sent to a peer.
+struct Sending {
+    /// The peer the file is sent to.
+    addr: SocketAddr,
+    /// Notifies the task sending the file once the peer accepted it.
+    accepted: Option<oneshot::Sender<()>>,
+}
+
+/// The number of IP addresses tracked by an [AcceptLimiter] beyond which those whose bucket is full are forgotten.
+const MAX_TRACKED_IPS: usize = 1024;
+
+/// Enforces an [AcceptRateLimit] on the inbound connections.
+struct AcceptLimiter {
+    limit: AcceptRateLimit,
+    global: Bucket,
+    per_ip: HashMap<IpAddr, Bucket>,
+    /// The connections refused since the last report.
+    refused: u64,
+    /// When the refused connections were last reported, if ever.
+    reported: Option<Instant>,
+}
+
+impl AcceptLimiter {
+    fn new(limit: AcceptRateLimit) -> Self {
+        Self {
+            limit,
+            global: Bucket::new(limit.global),
+            per_ip: HashMap::new(),
+            refused: 0,
+            reported: None,
+        }
+    }
+
+    /// Returns true if an inbound connection from `ip` is within the limit, taking a token from its buckets.
+    fn permits(&mut self, ip: IpAddr) -> bool {
+        if self.per_ip.len() >= MAX_TRACKED_IPS {
+            let rate = self.limit.per_ip;
+            self.per_ip.retain(|_, bucket| !bucket.is_full(rate));
+        }
+        let limit = self.limit;
+        let per_ip = self
+            .per_ip
+            .entry(ip)
+            .or_insert_with(|| Bucket::new(limit.per_ip));
+        // Both buckets are checked before taking from either, so a connection refused by one does not drain the other.
+        let permitted = per_ip.has_token(limit.per_ip) && self.global.has_token(limit.global);
+        if permitted {
+            per_ip.take();
+            self.global.take();
+        }
+        if !permitted {
+            self.refused += 1;
+            // Report at most once per second, as a flood would otherwise flood the logs too.
+            if self
+                .reported
+                .is_none_or(|reported| reported.elapsed() >= Duration::from_secs(1))
+            {
+                tracing::warn!(refused = self.refused, latest = %ip, "refused inbound connections over the accept rate limit");
+                self.refused = 0;
+                self.reported = Some(Instant::now());
+            }
+        }
+        permitted
+    }
+}
+
+/// A token bucket, refilled lazily based on the time elapsed since it was last used.
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Rate) -> Self {
+        Self {
+            tokens: f64::from(rate.burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: Rate) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(rate.per_sec)).min(f64::from(rate.burst));
+        self.refilled = now;
+    }
+
+    /// Returns true if a token is left to be taken.
+    fn has_token(&mut self, rate: Rate) -> bool {
+        self.refill(rate);
+        self.tokens >= 1.0
+    }
+
+    /// Takes a token, which [Self::has_token] must have found.
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// Returns true if the bucket refilled completely, so forgetting it changes nothing.
+    fn is_full(&mut self, rate: Rate) -> bool {
+        self.refill(rate);
+        self.tokens >= f64::from(rate.burst)
+    }
+}
+
+/// The messages recently received from a peer, keyed by original sender and id, oldest first.
+#[derive(Default)]
+struct Seen {
+    keys: HashSet<(String, u64)>,
+    order: VecDeque<(String, u64)>,
+}
+
+impl Seen {
+    /// Records the message, forgetting the oldest ones beyond `capacity`. Returns false if it was already recorded.
+    fn insert(&mut self, message: &Message, capacity: usize) -> bool {
+        let key = (message.sender.clone(), message.id);
+        if self.keys.contains(&key) {
+            return false;
+        }
+        if capacity == 0 {
+            return true;
+        }
+        while self.order.len() >= capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(key.clone());
+        self.order.push_back(key);
+        true
+    }
+}
+
+/// Announces the presence, if set, and the joined rooms to the peer of the connection.
+async fn announce(conn: &Connection, presence: &Option<Presence>, rooms: &HashSet<String>) {
+    if let Some(presence) = presence {
+        conn.send_command(
+            Box::new(crate::layers::presence::Cmd::Announce(presence.clone())),
+            Priority::High,
+        )
+        .await;
+    }
+    for room in rooms {
+        conn.send_command(
+            Box::new(crate::layers::room::Cmd::Join(room.clone())),
+            Priority::High,
+        )
+        .await;
+    }
+}
+
+/// Binds a listener to each of the specified addresses.
+///
+/// If more than one address fails to bind, the returned error lists the error of each.
+async fn bind_all(addrs: Vec<String>, config: &AmsConfig) -> Result<Vec<TcpListener>, AmsError> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    let mut failures = Vec::new();
+
+    for addr in addrs {
+        match bind(&addr, config).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => failures.push(e),
+        }
+    }
+
+    if failures.len() > 1 {
+        return Err(AmsError::Multiple(failures));
+    }
+    if let Some(failure) = failures.pop() {
+        return Err(failure);
+    }
+    if listeners.is_empty() {
+        return Err(AmsError::InvalidConfig(
+            "no addresses to bind to".to_string(),
+        ));
+    }
+    Ok(listeners)
+}
+
+/// Binds a listener to the first address that `addr` resolves to and can be bound, as [TcpListener::bind] does, with
+/// the socket options of the provided configuration.
+async fn bind(addr: &str, config: &AmsConfig) -> Result<TcpListener, AmsError> {
+    let resolved =
+        tokio::net::lookup_host(addr)
+            .await
+            .map_err(|source| AmsError::InvalidAddress {
+                addr: addr.to_string(),
+                source,
+            })?;
+    let mut last_err = None;
+    for addr in resolved {
+        match listen(addr, config) {
+            Ok(listener) => return Ok(listener),
+            Err(source) if source.kind() == std::io::ErrorKind::AddrInUse => {
+                last_err = Some(AmsError::AlreadyRunning { addr });
+            }
+            Err(source) => last_err = Some(AmsError::BindFailed { addr, source }),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| AmsError::InvalidAddress {
+        addr: addr.to_string(),
+        source: std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        ),
+    }))
+}
+
+/// Binds a listener to the specified address.
+fn listen(addr: SocketAddr, config: &AmsConfig) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(config.reuse_address)?;
+    socket.bind(addr)?;
+    socket.listen(config.listen_backlog)
+}
+
+/// Returns true if `addr` refers to one of our own listeners bound at `locals`.
+///
+/// A listener bound to an unspecified address (e.g. `0.0.0.0`) is also reachable through the loopback address.
+fn is_self(addr: SocketAddr, locals: &[SocketAddr]) -> bool {
+    locals.iter().any(|local| {
+        addr.port() == local.port()
+            && (addr.ip() == local.ip() || (local.ip().is_unspecified() && addr.ip().is_loopback()))
+    })
+}
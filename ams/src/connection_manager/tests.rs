@@ -0,0 +1,815 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{Ams, AmsConfig, Event, InboundMode};
+
+/// How long a test waits for an event before failing.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Binds an instance to an ephemeral port on the loopback interface, returning it and its address.
+async fn bind(config: AmsConfig) -> (Ams, SocketAddr) {
+    let ams = Ams::bind_with("127.0.0.1:0", config).await.unwrap();
+    let addr = ams.manager.addrs[0];
+    (ams, addr)
+}
+
+/// Binds an instance accepting every inbound connection.
+async fn bind_accepting() -> (Ams, SocketAddr) {
+    bind(
+        AmsConfig::builder()
+            .inbound_mode(InboundMode::AcceptAll)
+            .build(),
+    )
+    .await
+}
+
+/// Returns the next event for which `f` returns `Some`, skipping the others.
+async fn next<T>(ams: &mut Ams, mut f: impl FnMut(Event) -> Option<T>) -> T {
+    loop {
+        let event = ams
+            .next_event_timeout(EVENT_TIMEOUT)
+            .await
+            .expect("timed out waiting for an event");
+        if let Some(value) = f(event) {
+            return value;
+        }
+    }
+}
+
+/// Waits for a connection to be established, returning the peer's address.
+async fn established(ams: &mut Ams) -> SocketAddr {
+    next(ams, |event| match event {
+        Event::ConnectionEstablished { peer, .. } => Some(peer),
+        _ => None,
+    })
+    .await
+}
+
+/// Connects `a` to `b`, returning the address of `a` as seen by `b`.
+async fn connect(a: &mut Ams, b: &mut Ams, b_addr: SocketAddr) -> SocketAddr {
+    a.connect(b_addr).await.unwrap();
+    assert_eq!(established(a).await, b_addr);
+    established(b).await
+}
+
+#[tokio::test]
+async fn connecting_to_a_connected_peer_is_rejected() {
+    let (mut a, _) = bind(AmsConfig::default()).await;
+    let (mut b, b_addr) = bind_accepting().await;
+    let a_addr = connect(&mut a, &mut b, b_addr).await;
+
+    a.connect(b_addr).await.unwrap();
+    let rejected = next(&mut a, |event| match event {
+        Event::ConnectionRejected { peer } => Some(peer),
+        _ => None,
+    })
+    .await;
+    assert_eq!(rejected, b_addr);
+
+    // The original connection is unaffected.
+    assert!(a.is_connected(b_addr).await);
+    let message_id = a.send_message(b_addr, b"hello".to_vec()).await.unwrap();
+    let received = next(&mut b, |event| match event {
+        Event::MessageReceived {
+            peer, message_id, ..
+        } => Some((peer, message_id)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received, (a_addr, message_id));
+}
+
+/// Binds an instance accepting every inbound connection, whose stack is able to transfer files.
+async fn bind_with_files(config: crate::AmsConfigBuilder) -> (Ams, SocketAddr) {
+    use crate::layers::{file::File, ping::Ping, transmit::Transmit};
+
+    let stack = crate::controller::StackKind::custom::<(Ping, File, Transmit)>();
+    bind(
+        config
+            .inbound_mode(InboundMode::AcceptAll)
+            .stack(stack)
+            .build(),
+    )
+    .await
+}
+
+/// Writes a temporary file of the specified size to send, returning its path.
+fn temp_file(size: usize) -> std::path::PathBuf {
+    use rand_core::{OsRng, RngCore};
+
+    let path = std::env::temp_dir().join(format!("ams-test-{:016x}", OsRng.next_u64()));
+    std::fs::write(&path, vec![7; size]).unwrap();
+    path
+}
+
+/// Waits for the outcome of the transfer with the specified id, returning whether it completed.
+async fn transfer_outcome(ams: &mut Ams, id: u64) -> bool {
+    next(ams, |event| match event {
+        Event::TransferComplete { transfer_id, .. } if transfer_id == id => Some(true),
+        Event::TransferFailed { transfer_id, .. } if transfer_id == id => Some(false),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn file_is_received_intact() {
+    let (mut a, _) = bind_with_files(AmsConfig::builder()).await;
+    let (mut b, b_addr) = bind_with_files(AmsConfig::builder()).await;
+    connect(&mut a, &mut b, b_addr).await;
+    let path = temp_file(200 * 1024);
+
+    let id = a.send_file(b_addr, &path).await.unwrap();
+    let (received, name) = next(&mut b, |event| match event {
+        Event::FileReceived { path, name, .. } => Some((path, name)),
+        _ => None,
+    })
+    .await;
+    assert!(transfer_outcome(&mut a, id).await);
+    assert_eq!(
+        std::fs::read(&received).unwrap(),
+        std::fs::read(&path).unwrap()
+    );
+    assert_eq!(
+        Some(name.as_str()),
+        path.file_name().and_then(|name| name.to_str())
+    );
+    let _ = std::fs::remove_file(received);
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn file_larger_than_the_maximum_size_is_refused() {
+    let (mut a, _) = bind_with_files(AmsConfig::builder()).await;
+    let (mut b, b_addr) = bind_with_files(AmsConfig::builder().max_file_size(1024)).await;
+    connect(&mut a, &mut b, b_addr).await;
+    let path = temp_file(1025);
+
+    let id = a.send_file(b_addr, &path).await.unwrap();
+    assert!(!transfer_outcome(&mut a, id).await);
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn offered_file_is_only_received_once_accepted() {
+    let (mut a, _) = bind_with_files(AmsConfig::builder()).await;
+    let (mut b, b_addr) = bind_with_files(AmsConfig::builder().prompt_files(true)).await;
+    connect(&mut a, &mut b, b_addr).await;
+    let path = temp_file(1024);
+
+    for accept in [false, true] {
+        let id = a.send_file(b_addr, &path).await.unwrap();
+        let (size, response) = next(&mut b, |event| match event {
+            Event::FileOffered { size, response, .. } => Some((size, response)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(size, 1024);
+        response.send(accept).unwrap();
+        assert_eq!(transfer_outcome(&mut a, id).await, accept);
+    }
+    let received = next(&mut b, |event| match event {
+        Event::FileReceived { path, .. } => Some(path),
+        _ => None,
+    })
+    .await;
+    let _ = std::fs::remove_file(received);
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn file_sent_without_a_file_layer_fails() {
+    let (mut a, _) = bind(AmsConfig::default()).await;
+    let (mut b, b_addr) = bind_accepting().await;
+    connect(&mut a, &mut b, b_addr).await;
+    let path = temp_file(1024);
+
+    let id = a.send_file(b_addr, &path).await.unwrap();
+    assert!(!transfer_outcome(&mut a, id).await);
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn full_stack_exchanges_messages_reactions_and_pings() {
+    use crate::controller::StackKind;
+
+    let config = || {
+        AmsConfig::builder()
+            .inbound_mode(InboundMode::AcceptAll)
+            .stack(StackKind::Full)
+            .build()
+    };
+    let (mut a, _) = bind(config()).await;
+    let (mut b, b_addr) = bind(config()).await;
+    let a_addr = connect(&mut a, &mut b, b_addr).await;
+
+    let sent = a.send_message(b_addr, b"hello".to_vec()).await.unwrap();
+    let received = next(&mut b, |event| match event {
+        Event::MessageReceived { message_id, .. } => Some(message_id),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received, sent);
+
+    b.react(a_addr, received, "👍").await.unwrap();
+    let reaction = next(&mut a, |event| match event {
+        Event::Reaction {
+            message_id, emoji, ..
+        } => Some((message_id, emoji)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(reaction, (sent, "👍".to_string()));
+
+    assert!(a.ping(b_addr).await.is_some());
+}
+
+#[tokio::test]
+async fn stalled_handshake_is_rejected_without_counting_as_connected() {
+    let (mut b, b_addr) = bind(
+        AmsConfig::builder()
+            .inbound_mode(InboundMode::AcceptAll)
+            .handshake_timeout(Duration::from_millis(500))
+            .build(),
+    )
+    .await;
+
+    // Opens the TCP connection, but never performs the handshake.
+    let stream = tokio::net::TcpStream::connect(b_addr).await.unwrap();
+    let a_addr = stream.local_addr().unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let stats = b.stats().await;
+    assert_eq!(stats.connections, 0);
+    assert!(stats.per_connection.is_empty());
+    assert!(!b.is_connected(a_addr).await);
+
+    let rejected = next(&mut b, |event| match event {
+        Event::ConnectionRejected { peer } => Some(peer),
+        Event::ConnectionEstablished { .. } => panic!("the handshake was not performed"),
+        _ => None,
+    })
+    .await;
+    assert_eq!(rejected, a_addr);
+}
+
+#[tokio::test]
+async fn abandoned_confirmations_are_forgotten() {
+    let (b, b_addr) = bind_accepting().await;
+
+    // Messages to a connection still being established are queued until the handshake is performed, which it never is.
+    let stream = tokio::net::TcpStream::connect(b_addr).await.unwrap();
+    let a_addr = stream.local_addr().unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for _ in 0..16 {
+        let confirmed = b.send_message_confirmed(a_addr, b"hello".to_vec());
+        assert!(
+            tokio::time::timeout(Duration::ZERO, confirmed)
+                .await
+                .is_err()
+        );
+    }
+
+    tokio::time::sleep(super::CONFIRMATION_SWEEP * 2).await;
+    let (resp, rx) = tokio::sync::oneshot::channel();
+    b.send_command(crate::Command::PendingConfirmations { resp })
+        .await
+        .unwrap();
+    assert_eq!(rx.await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn stalled_consumer_only_misses_droppable_events() {
+    let (mut a, _) = bind(AmsConfig::default()).await;
+    let (mut b, b_addr) = bind(
+        AmsConfig::builder()
+            .inbound_mode(InboundMode::AcceptAll)
+            .event_capacity(4)
+            .build(),
+    )
+    .await;
+    let a_addr = connect(&mut a, &mut b, b_addr).await;
+
+    // The consumer of `b` stops draining events while messages are received, then `a` disconnects.
+    for _ in 0..16 {
+        let message_id = a.send_message(b_addr, b"hello".to_vec()).await.unwrap();
+        let sent = next(&mut a, |event| match event {
+            Event::MessageSent { message_id, .. } => Some(message_id),
+            _ => None,
+        })
+        .await;
+        assert_eq!(sent, message_id);
+    }
+    a.disconnect(b_addr).await.unwrap();
+    // The messages may still be in flight once written by `a`.
+    tokio::time::timeout(EVENT_TIMEOUT, async {
+        while b.stats().await.events_dropped < 12 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("timed out waiting for the messages to be received");
+
+    for _ in 0..4 {
+        let event = b.next_event_timeout(EVENT_TIMEOUT).await;
+        assert!(matches!(event, Some(Event::MessageReceived { .. })));
+    }
+    let event = b.next_event_timeout(EVENT_TIMEOUT).await;
+    assert!(matches!(event, Some(Event::ConnectionDisconnected { peer, .. }) if peer == a_addr));
+}
+
+/// Sends a message from `sender` to `peer`, asserting that `receiver` receives it.
+async fn assert_message_received(sender: &Ams, receiver: &mut Ams, peer: SocketAddr) {
+    let message_id = sender.send_message(peer, b"hello".to_vec()).await.unwrap();
+    let received = next(receiver, |event| match event {
+        Event::MessageReceived {
+            message_id,
+            payload,
+            ..
+        } => Some((message_id, payload)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received, (message_id, b"hello".to_vec()));
+}
+
+#[cfg(feature = "json")]
+#[tokio::test]
+async fn messages_are_exchanged_in_the_configured_format() {
+    use crate::format::MessageFormat;
+
+    let config = |format| AmsConfig::builder().format(format).build();
+    let (mut a, _) = bind(config(MessageFormat::Json)).await;
+    let (mut b, b_addr) = bind(AmsConfig {
+        inbound_mode: InboundMode::AcceptAll,
+        ..config(MessageFormat::Json)
+    })
+    .await;
+    let a_addr = connect(&mut a, &mut b, b_addr).await;
+    assert_message_received(&a, &mut b, b_addr).await;
+    assert_message_received(&b, &mut a, a_addr).await;
+
+    // A peer configured with another format is rejected.
+    let (mut c, _) = bind(config(MessageFormat::Postcard)).await;
+    c.connect(b_addr).await.unwrap();
+    assert!(!connection_outcome(&mut c).await);
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn messages_are_exchanged_in_messagepack() {
+    let config = AmsConfig::builder()
+        .format(crate::format::MessageFormat::MessagePack)
+        .build();
+    let (mut a, _) = bind(config.clone()).await;
+    let (mut b, b_addr) = bind(AmsConfig {
+        inbound_mode: InboundMode::AcceptAll,
+        ..config
+    })
+    .await;
+    let a_addr = connect(&mut a, &mut b, b_addr).await;
+    assert_message_received(&a, &mut b, b_addr).await;
+    assert_message_received(&b, &mut a, a_addr).await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn messages_are_exchanged_over_unix_domain_sockets() {
+    use rand_core::{OsRng, RngCore};
+
+    let path =
+        |name| std::env::temp_dir().join(format!("ams-{name}-{:016x}.sock", OsRng.next_u64()));
+    let (a_path, b_path) = (path("a"), path("b"));
+    let mut a = Ams::bind_unix(&a_path).await.unwrap();
+    let config = AmsConfig::builder()
+        .inbound_mode(InboundMode::AcceptAll)
+        .build();
+    let mut b = Ams::bind_unix_with(&b_path, config).await.unwrap();
+
+    let b_addr = a.connect_unix(&b_path).await.unwrap();
+    assert_eq!(established(&mut a).await, b_addr);
+    let a_addr = established(&mut b).await;
+    assert!(a.is_connected(b_addr).await);
+
+    assert_message_received(&a, &mut b, b_addr).await;
+    assert_message_received(&b, &mut a, a_addr).await;
+
+    a.shutdown().await;
+    b.shutdown().await;
+    assert!(!a_path.exists());
+    assert!(!b_path.exists());
+}
+
+#[tokio::test]
+async fn connecting_to_self_is_rejected() {
+    let (mut a, a_addr) = bind_accepting().await;
+
+    a.connect(a_addr).await.unwrap();
+    let rejected = next(&mut a, |event| match event {
+        Event::ConnectionRejected { peer } => Some(peer),
+        Event::ConnectionEstablished { .. } => panic!("connected to self"),
+        _ => None,
+    })
+    .await;
+    assert_eq!(rejected, a_addr);
+    assert!(!a.is_connected(a_addr).await);
+}
+
+#[test]
+fn loopback_address_is_self_when_bound_to_the_unspecified_address() {
+    let locals = ["0.0.0.0:4000".parse().unwrap()];
+    assert!(super::is_self("127.0.0.1:4000".parse().unwrap(), &locals));
+    assert!(!super::is_self("127.0.0.1:4001".parse().unwrap(), &locals));
+    assert!(!super::is_self("10.0.0.1:4000".parse().unwrap(), &locals));
+}
+
+/// Binds an instance accepting the inbound connections permitted by the policy.
+async fn bind_with_policy(policy: crate::AcceptPolicy) -> (Ams, SocketAddr) {
+    bind(
+        AmsConfig::builder()
+            .inbound_mode(InboundMode::AcceptAll)
+            .accept_policy(policy)
+            .build(),
+    )
+    .await
+}
+
+/// Waits for the outcome of a connection attempt, returning whether it was established.
+async fn connection_outcome(ams: &mut Ams) -> bool {
+    next(ams, |event| match event {
+        Event::ConnectionEstablished { .. } => Some(true),
+        Event::ConnectionRejected { .. } => Some(false),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn connection_from_outside_the_allowlist_is_rejected() {
+    let (mut a, _) = bind(AmsConfig::default()).await;
+    let (mut b, b_addr) = bind_with_policy(crate::AcceptPolicy {
+        allowlist: Some(["10.0.0.1".parse().unwrap()].into()),
+        ..Default::default()
+    })
+    .await;
+
+    a.connect(b_addr).await.unwrap();
+    assert!(!connection_outcome(&mut a).await);
+    assert!(!connection_outcome(&mut b).await);
+
+    let (mut c, c_addr) = bind_with_policy(crate::AcceptPolicy {
+        allowlist: Some(["127.0.0.1".parse().unwrap()].into()),
+        ..Default::default()
+    })
+    .await;
+    a.connect(c_addr).await.unwrap();
+    assert!(connection_outcome(&mut a).await);
+    assert!(connection_outcome(&mut c).await);
+}
+
+#[tokio::test]
+async fn connection_over_the_maximum_is_rejected() {
+    let (mut a, _) = bind(AmsConfig::default()).await;
+    let (mut b, _) = bind(AmsConfig::default()).await;
+    let (mut c, c_addr) = bind_with_policy(crate::AcceptPolicy {
+        max_connections: Some(1),
+        ..Default::default()
+    })
+    .await;
+    connect(&mut a, &mut c, c_addr).await;
+
+    b.connect(c_addr).await.unwrap();
+    assert!(!connection_outcome(&mut b).await);
+    assert!(!connection_outcome(&mut c).await);
+
+    // Once the connection is closed, another one is permitted.
+    a.disconnect(c_addr).await.unwrap();
+    next(&mut c, |event| match event {
+        Event::ConnectionDisconnected { .. } => Some(()),
+        _ => None,
+    })
+    .await;
+    b.connect(c_addr).await.unwrap();
+    assert!(connection_outcome(&mut b).await);
+    assert!(connection_outcome(&mut c).await);
+}
+
+/// Opens a TCP connection over the loopback interface, returning the connecting end.
+async fn tcp_stream() -> tokio::net::TcpStream {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stream, _) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+    stream.unwrap()
+}
+
+#[tokio::test]
+async fn nodelay_is_applied_as_configured() {
+    for nodelay in [true, false] {
+        let stream = tcp_stream().await;
+        let addr = stream.peer_addr().unwrap();
+        let config = AmsConfig::builder().tcp_nodelay(nodelay).build();
+        super::configure_stream(&stream, addr, &config);
+        assert_eq!(stream.nodelay().unwrap(), nodelay);
+    }
+}
+
+#[tokio::test]
+async fn keepalive_is_applied_as_configured() {
+    let stream = tcp_stream().await;
+    let addr = stream.peer_addr().unwrap();
+    super::configure_stream(&stream, addr, &AmsConfig::default());
+    assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+
+    let stream = tcp_stream().await;
+    let keepalive = crate::TcpKeepalive {
+        idle: Duration::from_secs(30),
+        interval: Some(Duration::from_secs(5)),
+        retries: Some(3),
+    };
+    let config = AmsConfig::builder().tcp_keepalive(keepalive).build();
+    super::configure_stream(&stream, addr, &config);
+    let socket = socket2::SockRef::from(&stream);
+    assert!(socket.keepalive().unwrap());
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(socket.tcp_keepalive_time().unwrap(), keepalive.idle);
+        assert_eq!(
+            socket.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(5)
+        );
+        assert_eq!(socket.tcp_keepalive_retries().unwrap(), 3);
+    }
+}
+
+#[tokio::test]
+async fn stopped_instance_reports_that_it_is_closed() {
+    let (mut a, _) = bind(AmsConfig::default()).await;
+    let (_, b_addr) = bind_accepting().await;
+
+    a.manager.handle.abort();
+    assert!(a.next_event().await.is_none());
+    assert!(matches!(
+        a.send_message(b_addr, b"hello".to_vec()).await,
+        Err(crate::SendError::Closed)
+    ));
+    assert!(!a.is_connected(b_addr).await);
+}
+
+/// A layer that sends every outgoing frame twice.
+struct Duplicates;
+
+impl crate::layers::Layer for Duplicates {
+    const NAME: &'static str = "duplicates";
+
+    type Command = ();
+
+    async fn initialize(
+        _stream: &mut crate::transport::Transport,
+        _init: &crate::layers::Init,
+    ) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<bytes::BytesMut> {
+        None
+    }
+
+    fn handle_incoming_frame(
+        &mut self,
+        _frame: &mut bytes::BytesMut,
+    ) -> Result<crate::layers::Incoming, String> {
+        Ok(crate::layers::Incoming::Forward(None))
+    }
+
+    fn handle_outgoing_frame(&mut self, _frame: &mut bytes::BytesMut) {}
+
+    fn split_outgoing_frame(
+        &mut self,
+        frame: bytes::BytesMut,
+    ) -> Result<Vec<bytes::BytesMut>, crate::FailureReason> {
+        Ok(vec![frame.clone(), frame])
+    }
+}
+
+#[tokio::test]
+async fn duplicate_frame_is_reported_as_dropped_by_the_sequence_layer() {
+    use crate::layers::{Layer, sequence::Sequence, transmit::Transmit};
+
+    let stack = crate::controller::StackKind::custom::<(Duplicates, Sequence, Transmit)>();
+    let config = |mode| AmsConfig::builder().inbound_mode(mode).stack(stack).build();
+    let (mut a, _) = bind(config(InboundMode::RejectAll)).await;
+    let (mut b, b_addr) = bind(config(InboundMode::AcceptAll)).await;
+    let a_addr = connect(&mut a, &mut b, b_addr).await;
+
+    a.send_message(b_addr, b"hello".to_vec()).await.unwrap();
+    let (peer, layer) = next(&mut b, |event| match event {
+        Event::LayerDropped { peer, layer, .. } => Some((peer, layer)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(peer, a_addr);
+    assert_eq!(layer, Sequence::NAME);
+}
+
+/// A layer that panics on every incoming frame, or while being initialized if `INITIALIZING`.
+struct Panics<const INITIALIZING: bool = false>;
+
+impl<const INITIALIZING: bool> crate::layers::Layer for Panics<INITIALIZING> {
+    const NAME: &'static str = "panics";
+
+    type Command = ();
+
+    async fn initialize(
+        _stream: &mut crate::transport::Transport,
+        _init: &crate::layers::Init,
+    ) -> Result<Self, String> {
+        if INITIALIZING {
+            panic!("initializing");
+        }
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<bytes::BytesMut> {
+        None
+    }
+
+    fn handle_incoming_frame(
+        &mut self,
+        _frame: &mut bytes::BytesMut,
+    ) -> Result<crate::layers::Incoming, String> {
+        panic!("incoming frame");
+    }
+
+    fn handle_outgoing_frame(&mut self, _frame: &mut bytes::BytesMut) {}
+}
+
+#[tokio::test]
+async fn connection_panic_is_reported_as_a_disconnect() {
+    use crate::layers::transmit::Transmit;
+
+    let stack = crate::controller::StackKind::custom::<(Panics, Transmit)>();
+    let config = |mode| AmsConfig::builder().inbound_mode(mode).stack(stack).build();
+    let (mut a, _) = bind(config(InboundMode::RejectAll)).await;
+    let (mut b, b_addr) = bind(config(InboundMode::AcceptAll)).await;
+    let a_addr = connect(&mut a, &mut b, b_addr).await;
+
+    a.send_message(b_addr, b"hello".to_vec()).await.unwrap();
+    let disconnected = next(&mut b, |event| match event {
+        Event::ConnectionDisconnected { peer, reason } => Some((peer, reason)),
+        Event::MessageReceived { .. } => panic!("the message was received"),
+        _ => None,
+    })
+    .await;
+    assert_eq!(disconnected, (a_addr, crate::DisconnectReason::Panicked));
+    assert!(!b.is_connected(a_addr).await);
+
+    // A connection panicking before being established is rejected instead.
+    let stack = crate::controller::StackKind::custom::<(Panics<true>, Transmit)>();
+    let config = |mode| AmsConfig::builder().inbound_mode(mode).stack(stack).build();
+    let (mut c, _) = bind(config(InboundMode::RejectAll)).await;
+    let (mut d, d_addr) = bind(config(InboundMode::AcceptAll)).await;
+    c.connect(d_addr).await.unwrap();
+    assert!(!connection_outcome(&mut c).await);
+    assert!(!connection_outcome(&mut d).await);
+    assert!(!c.is_connected(d_addr).await);
+}
+
+#[test]
+fn duplicate_messages_are_only_received_once_within_the_window() {
+    let message = |sender: &str, id| crate::api::Message {
+        id,
+        payload: Vec::new(),
+        sender: sender.to_string(),
+    };
+    let mut seen = super::Seen::default();
+    assert!(seen.insert(&message("a", 1), 2));
+    assert!(!seen.insert(&message("a", 1), 2));
+    // Messages are keyed by their original sender as well as their id.
+    assert!(seen.insert(&message("b", 1), 2));
+    // The window only holds the two most recent messages, so the first one is forgotten.
+    assert!(seen.insert(&message("c", 1), 2));
+    assert!(seen.insert(&message("a", 1), 2));
+
+    // A window of zero disables suppression.
+    let mut seen = super::Seen::default();
+    assert!(seen.insert(&message("a", 1), 0));
+    assert!(seen.insert(&message("a", 1), 0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn accept_limiter_refuses_connections_over_the_burst_until_refilled() {
+    use crate::{AcceptRateLimit, Rate};
+
+    let a = "10.0.0.1".parse().unwrap();
+    let b = "10.0.0.2".parse().unwrap();
+    let mut limiter = super::AcceptLimiter::new(AcceptRateLimit {
+        per_ip: Rate {
+            per_sec: 1,
+            burst: 2,
+        },
+        global: Rate {
+            per_sec: 1,
+            burst: 3,
+        },
+    });
+    assert!(limiter.permits(a));
+    assert!(limiter.permits(a));
+    assert!(!limiter.permits(a));
+    // Another address has its own bucket, but shares the global one.
+    assert!(limiter.permits(b));
+    assert!(!limiter.permits(b));
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(limiter.permits(a));
+    assert!(!limiter.permits(a));
+}
+
+#[tokio::test(start_paused = true)]
+async fn accept_limiter_only_takes_tokens_from_permitted_connections() {
+    use crate::{AcceptRateLimit, Rate};
+
+    let a = "10.0.0.1".parse().unwrap();
+    let b = "10.0.0.2".parse().unwrap();
+    let mut limiter = super::AcceptLimiter::new(AcceptRateLimit {
+        per_ip: Rate {
+            per_sec: 1,
+            burst: 1,
+        },
+        global: Rate {
+            per_sec: 10,
+            burst: 1,
+        },
+    });
+    assert!(limiter.permits(a));
+    // Refused by the global bucket, which leaves the address's own token untouched.
+    assert!(!limiter.permits(b));
+
+    tokio::time::advance(Duration::from_millis(100)).await;
+    assert!(limiter.permits(b));
+}
+
+#[tokio::test]
+async fn zero_accept_rate_is_invalid() {
+    use crate::{AcceptRateLimit, AmsError, Rate};
+
+    let zero = [
+        Rate {
+            per_sec: 0,
+            burst: 10,
+        },
+        Rate {
+            per_sec: 5,
+            burst: 0,
+        },
+    ];
+    for rate in zero {
+        for limit in [
+            AcceptRateLimit {
+                per_ip: rate,
+                ..Default::default()
+            },
+            AcceptRateLimit {
+                global: rate,
+                ..Default::default()
+            },
+        ] {
+            let config = AmsConfig::builder().accept_rate_limit(limit).build();
+            assert!(matches!(
+                Ams::bind_with("127.0.0.1:0", config).await,
+                Err(AmsError::InvalidConfig(_))
+            ));
+        }
+    }
+}
+
+#[tokio::test]
+async fn inbound_connections_are_handled_per_the_inbound_mode() {
+    let (mut a, _) = bind(AmsConfig::default()).await;
+
+    let (mut b, b_addr) = bind_accepting().await;
+    a.connect(b_addr).await.unwrap();
+    assert!(connection_outcome(&mut a).await);
+    assert!(connection_outcome(&mut b).await);
+
+    let config = AmsConfig::builder()
+        .inbound_mode(InboundMode::RejectAll)
+        .build();
+    let (mut c, c_addr) = bind(config).await;
+    a.connect(c_addr).await.unwrap();
+    assert!(!connection_outcome(&mut a).await);
+    assert!(!connection_outcome(&mut c).await);
+
+    // Prompt is the default mode. The connection is only established if the application accepts it.
+    let (mut d, d_addr) = bind(AmsConfig::default()).await;
+    for accept in [false, true] {
+        a.connect(d_addr).await.unwrap();
+        let response = next(&mut d, |event| match event {
+            Event::ConnectionRequested { response, .. } => Some(response),
+            Event::ConnectionEstablished { .. } => panic!("established without being accepted"),
+            _ => None,
+        })
+        .await;
+        response.send(accept).unwrap();
+        assert_eq!(connection_outcome(&mut a).await, accept);
+        assert_eq!(a.is_connected(d_addr).await, accept);
+    }
+    assert!(connection_outcome(&mut d).await);
+}
@@ -1,195 +1,334 @@
+//! The [Controller] trait and its implementations for tuples of [Layer]s.
 use bytes::BytesMut;
-use tokio::net::TcpStream;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-use std::any::Any;
+use std::{any::Any, pin::Pin};
 
-use crate::layers::Layer;
+use crate::{
+    FailureReason,
+    layers::{
+        Dropped, Incoming, Init, Layer, Signal, auth::Auth, file::File, goodbye::Goodbye,
+        identity::Identity, ping::Ping, presence::Presence, react::React, room::Room,
+        server::ServerRelay, transmit::Transmit, typing::Typing,
+    },
+    transport::Transport,
+};
 
 /// A Controller is responsible for processing frames from a remote peer or commands from the AMS manager.
 ///
 /// While this trait could be implemented directly, it is intended to be composed of multiple [Layer]s to form a
 /// processing pipeline. Since this is the intended usage, documentation regarding the trait method behaviors
-/// will refer to the layered usage.
+/// will refer to the layered usage. It is implemented for tuples of up to 12 layers, the first being the closest to
+/// the wire.
 pub trait Controller: Send + 'static {
+    /// The [Layer::NAME] of each layer in the stack, starting with the layer closest to the wire.
+    ///
+    /// Exchanged with the remote peer when connecting, so that peers using different stacks reject the connection
+    /// instead of misreading each other's frames.
+    const LAYERS: &'static [&'static str];
+
     /// Initializes each layer in the controller stack, returning a tuple of all layers initialied state.
+    ///
+    /// Layers are initialized in order, starting with the layer closest to the wire. Returns the error of the first
+    /// layer that failed to initialize, if any.
     fn initialize(
-        stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
-    ) -> impl std::future::Future<Output = Self> + std::marker::Send
+        stream: &mut Transport,
+        init: &Init,
+    ) -> impl std::future::Future<Output = Result<Self, String>> + std::marker::Send
     where
         Self: Sized + Send;
 
+    /// Returns the nickname the remote peer introduced itself with, as reported by the first layer that exchanged one.
+    /// See [Layer::nickname].
+    fn nickname(&self) -> Option<&str>;
+
     /// Processes a command from the manager.
     ///
     /// This method will search through each layer in the controller stack to find the layer that can handle the
     /// command. Once found, it will call that layer's [Layer::handle_cmd] method. If the layer returns some bytes,
     /// those bytes will be sent back up the layer stack from it's current location to be transmitted to the remote
-    /// peer.
-    fn process_cmd(&mut self, cmd: Box<dyn std::any::Any + Send>) -> Option<BytesMut>;
+    /// peer. Since layers may split a frame on its way (see [Layer::split_outgoing_frame]), this returns every frame
+    /// to transmit, in order. Returns no frames if the layer returned none, and [FailureReason::Unhandled] if no layer
+    /// handled the command.
+    fn process_cmd(
+        &mut self,
+        cmd: Box<dyn std::any::Any + Send>,
+    ) -> Result<Vec<BytesMut>, FailureReason>;
 
     /// Process an incoming frame from a remote peer.
     ///
-    /// This method will pass the frame through each layer in the controller stack, allowing each layer to inspect and
-    /// modify the frame as needed. Any layer may return a [crate::Command], which will be collected and sent back
-    /// to the manager after all layers have processed the frame.
-    fn process_incoming_frame(&mut self, frame: &mut bytes::BytesMut) -> Vec<crate::Command>;
+    /// This method will pass the frame through each layer in the controller stack, starting with the layer closest to
+    /// the wire, allowing each layer to inspect and modify the frame as needed. Any layer may return a [Signal],
+    /// which will be collected and sent back to the manager after all layers have processed the frame. Once a layer
+    /// has handled (or replied to) the frame, it is not passed to any further layers. If a layer discards the frame,
+    /// processing stops and a [Dropped] describing the layer and reason is returned instead. A reply that cannot be
+    /// split into frames, see [Layer::split_outgoing_frame], is not sent.
+    fn process_incoming_frame(&mut self, frame: &mut bytes::BytesMut)
+    -> Result<Processed, Dropped>;
 }
 
-// TODO: Turn this into a proc macro
-#[allow(unused_mut)]
-#[allow(non_snake_case)]
-impl<L1: Layer> Controller for (L1,) {
-    async fn initialize(stream: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Self
-    where
-        Self: Sized + Send,
-    {
-        (L1::initialize(stream).await,)
-    }
-
-    fn process_cmd(&mut self, cmd: Box<dyn Any + Send>) -> Option<BytesMut> {
-        let (L1,) = self;
-
-        if cmd.is::<L1::Command>() {
-            let mut bytes = L1.handle_cmd(
-                *cmd.downcast::<L1::Command>()
-                    .expect("type validated through Any::is."),
-            );
+/// The result of processing an incoming frame through a controller stack.
+#[derive(Default)]
+pub struct Processed {
+    /// The signals returned by the layers, to be sent to the manager.
+    pub signals: Vec<Signal>,
+    /// The frames to send back to the remote peer, already processed by the layers closer to the wire than the layer
+    /// that replied.
+    pub replies: Vec<BytesMut>,
+}
 
-            return bytes;
-        }
-        None
+impl Processed {
+    /// Records the signal of the layer that handled the frame.
+    fn with_signal(mut self, signal: Option<Signal>) -> Self {
+        self.signals.extend(signal);
+        self
     }
 
-    fn process_incoming_frame(&mut self, mut frame: &mut BytesMut) -> Vec<crate::Command> {
-        let (L,) = self;
-        let mut cmds = Vec::new();
-
-        if let Some(cmd) = L.handle_incoming_frame(frame) {
-            cmds.push(cmd);
-        }
-
-        cmds
+    /// Records the reply of the layer that handled the frame.
+    fn with_replies(mut self, replies: Vec<BytesMut>) -> Self {
+        self.replies = replies;
+        self
     }
 }
 
-#[allow(unused_mut)]
-#[allow(non_snake_case)]
-impl<L1: Layer, L2: Layer> Controller for (L1, L2) {
-    async fn initialize(stream: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Self {
-        (L1::initialize(stream).await, L2::initialize(stream).await)
+/// Passes the outgoing frames of a layer through the layer below it, closer to the wire.
+fn outgoing<L: Layer>(
+    layer: &mut L,
+    frames: Vec<BytesMut>,
+) -> Result<Vec<BytesMut>, FailureReason> {
+    let mut split = Vec::with_capacity(frames.len());
+    for mut frame in frames {
+        layer.handle_outgoing_frame(&mut frame);
+        split.extend(layer.split_outgoing_frame(frame)?);
     }
+    Ok(split)
+}
 
-    fn process_cmd(&mut self, cmd: Box<dyn Any + Send>) -> Option<BytesMut> {
-        let (L1, L2) = self;
+/// The default controller stack, which authenticates the peers if they are configured with a token, introduces them
+/// by their nickname, transmits messages as is and answers pings.
+pub type Unsecure = (Auth, Identity, Ping, Transmit);
+
+/// The [Unsecure] stack, additionally able to relay messages through a server.
+pub type Relay = (Auth, Identity, Ping, ServerRelay, Transmit);
+
+/// The [Relay] stack, additionally able to say goodbye, and to exchange presence, typing notifications, room messages,
+/// reactions and files.
+pub type Full = (
+    Auth,
+    Identity,
+    Ping,
+    Goodbye,
+    Presence,
+    Typing,
+    Room,
+    React,
+    File,
+    ServerRelay,
+    Transmit,
+);
+
+/// Selects the controller stack used by a connection at runtime.
+///
+/// Since each stack is a distinct [Controller] type, the initialized controller is boxed behind a trait object so that
+/// connections using different stacks can be managed uniformly. Both peers of a connection must use stacks made of the
+/// same layers, else the connection is rejected with [crate::Event::ConnectionRejected].
+#[derive(Debug, Clone, Copy, Default)]
+pub enum StackKind {
+    /// The [Unsecure] stack.
+    #[default]
+    Unsecure,
+    /// The [Relay] stack.
+    Relay,
+    /// The [Full] stack.
+    Full,
+    /// A user provided stack, created with [StackKind::custom].
+    Custom(CustomStack),
+}
 
-        if cmd.is::<L1::Command>() {
-            let mut bytes = L1.handle_cmd(
-                *cmd.downcast::<L1::Command>()
-                    .expect("type validated through Any::is."),
-            );
+impl StackKind {
+    /// Returns a [StackKind] selecting the `C` controller stack.
+    pub fn custom<C: Controller>() -> Self {
+        Self::Custom(CustomStack {
+            name: std::any::type_name::<C>(),
+            layers: C::LAYERS,
+            initialize: initialize_boxed::<C>,
+        })
+    }
 
-            return bytes;
+    /// Returns the [Controller::LAYERS] of the selected controller stack.
+    pub(crate) fn layers(self) -> &'static [&'static str] {
+        match self {
+            Self::Unsecure => Unsecure::LAYERS,
+            Self::Relay => Relay::LAYERS,
+            Self::Full => Full::LAYERS,
+            Self::Custom(stack) => stack.layers,
         }
+    }
 
-        if cmd.is::<L2::Command>() {
-            let mut bytes = L2.handle_cmd(
-                *cmd.downcast::<L2::Command>()
-                    .expect("type validated through Any::is."),
-            );
+    /// Initializes the selected controller stack.
+    pub(crate) async fn initialize(
+        self,
+        stream: &mut Transport,
+        init: &Init,
+    ) -> Result<Box<dyn DynController>, String> {
+        match self {
+            Self::Unsecure => initialize_boxed::<Unsecure>(stream, init).await,
+            Self::Relay => initialize_boxed::<Relay>(stream, init).await,
+            Self::Full => initialize_boxed::<Full>(stream, init).await,
+            Self::Custom(stack) => (stack.initialize)(stream, init).await,
+        }
+    }
+}
 
-            if let Some(ref mut bytes) = bytes {
-                L1.handle_outgoing_frame(bytes);
-            }
+/// A user provided controller stack. See [StackKind::custom].
+#[derive(Clone, Copy)]
+pub struct CustomStack {
+    /// The type name of the controller, for debugging.
+    name: &'static str,
+    /// See [Controller::LAYERS].
+    layers: &'static [&'static str],
+    /// Initializes the controller, boxing it.
+    initialize: for<'a> fn(&'a mut Transport, &'a Init) -> BoxedController<'a>,
+}
 
-            return bytes;
-        }
-        None
+impl std::fmt::Debug for CustomStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CustomStack").field(&self.name).finish()
     }
+}
 
-    fn process_incoming_frame(&mut self, frame: &mut bytes::BytesMut) -> Vec<crate::Command> {
-        let (L1, L2) = self;
-        let mut cmds = Vec::new();
-        let mut frame_ref = frame;
+/// A future resolving to an initialized, boxed controller.
+type BoxedController<'a> =
+    Pin<Box<dyn Future<Output = Result<Box<dyn DynController>, String>> + Send + 'a>>;
+
+/// Initializes the `C` controller stack, boxing it.
+fn initialize_boxed<'a, C: Controller>(
+    stream: &'a mut Transport,
+    init: &'a Init,
+) -> BoxedController<'a> {
+    Box::pin(async move {
+        C::initialize(stream, init)
+            .await
+            .map(|controller| Box::new(controller) as Box<dyn DynController>)
+    })
+}
 
-        if let Some(cmd) = L2.handle_incoming_frame(frame_ref) {
-            cmds.push(cmd);
-        }
+/// An object safe subset of [Controller], implemented for every controller.
+pub(crate) trait DynController: Send {
+    /// See [Controller::process_cmd].
+    fn process_cmd(&mut self, cmd: Box<dyn Any + Send>) -> Result<Vec<BytesMut>, FailureReason>;
 
-        if let Some(cmd) = L1.handle_incoming_frame(frame_ref) {
-            cmds.push(cmd);
-        }
-        cmds
-    }
+    /// See [Controller::process_incoming_frame].
+    fn process_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Processed, Dropped>;
+
+    /// See [Controller::nickname].
+    fn nickname(&self) -> Option<&str>;
 }
 
-#[allow(unused_mut)]
-#[allow(non_snake_case)]
-impl<L1: Layer, L2: Layer, L3: Layer> Controller for (L1, L2, L3) {
-    async fn initialize(stream: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Self {
-        (
-            L1::initialize(stream).await,
-            L2::initialize(stream).await,
-            L3::initialize(stream).await,
-        )
+impl<C: Controller> DynController for C {
+    fn process_cmd(&mut self, cmd: Box<dyn Any + Send>) -> Result<Vec<BytesMut>, FailureReason> {
+        Controller::process_cmd(self, cmd)
     }
 
-    fn process_cmd(&mut self, cmd: Box<dyn Any + Send>) -> Option<BytesMut> {
-        let (L1, L2, L3) = self;
+    fn process_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Processed, Dropped> {
+        Controller::process_incoming_frame(self, frame)
+    }
 
-        if cmd.is::<L1::Command>() {
-            let mut bytes = L1.handle_cmd(
-                *cmd.downcast::<L1::Command>()
-                    .expect("type validated through Any::is."),
-            );
+    fn nickname(&self) -> Option<&str> {
+        Controller::nickname(self)
+    }
+}
 
-            return bytes;
+/// Processes a command with the first layer handling it, passing its frames through the layers below it.
+///
+/// The layers closer to the wire than the current one are accumulated in `[..]`, nearest first.
+macro_rules! process_cmd {
+    ($cmd:ident; [$($below:ident),*];) => {};
+    ($cmd:ident; [$($below:ident),*]; $layer:ident $(, $rest:ident)*) => {
+        if $cmd.is::<$layer::Command>() {
+            let frames = $layer
+                .handle_cmd(
+                    *$cmd
+                        .downcast::<$layer::Command>()
+                        .expect("type validated through Any::is."),
+                )
+                .into_iter()
+                .collect();
+            $(let frames = outgoing($below, frames)?;)*
+            return Ok(frames);
         }
+        process_cmd!($cmd; [$layer $(, $below)*]; $($rest),*);
+    };
+}
 
-        if cmd.is::<L2::Command>() {
-            let mut bytes = L2.handle_cmd(
-                *cmd.downcast::<L2::Command>()
-                    .expect("type validated through Any::is."),
-            );
-
-            if let Some(ref mut bytes) = bytes {
-                L1.handle_outgoing_frame(bytes);
+/// Passes an incoming frame through each layer, until one handles or replies to it.
+///
+/// The layers closer to the wire than the current one are accumulated in `[..]`, nearest first.
+macro_rules! process_incoming_frame {
+    ($frame:ident, $processed:ident; [$($below:ident),*];) => {};
+    ($frame:ident, $processed:ident; [$($below:ident),*]; $layer:ident $(, $rest:ident)*) => {
+        match $layer
+            .handle_incoming_frame($frame)
+            .map_err(Dropped::new::<$layer>)?
+        {
+            Incoming::Forward(signal) => $processed.signals.extend(signal),
+            Incoming::Handled(signal) => return Ok($processed.with_signal(signal)),
+            Incoming::Reply(reply) => {
+                let replies: Result<Vec<BytesMut>, FailureReason> = Ok(vec![reply]);
+                $(let replies = replies.and_then(|replies| outgoing($below, replies));)*
+                return Ok($processed.with_replies(replies.unwrap_or_default()));
             }
-
-            return bytes;
         }
+        process_incoming_frame!($frame, $processed; [$layer $(, $below)*]; $($rest),*);
+    };
+}
 
-        if cmd.is::<L3::Command>() {
-            let mut bytes = L3.handle_cmd(
-                *cmd.downcast::<L3::Command>()
-                    .expect("type validated through Any::is."),
-            );
+/// Implements [Controller] for a tuple of [Layer]s, the first being the closest to the wire.
+macro_rules! impl_controller {
+    ($($layer:ident),+) => {
+        #[allow(non_snake_case)]
+        impl<$($layer: Layer),+> Controller for ($($layer,)+) {
+            const LAYERS: &'static [&'static str] = &[$($layer::NAME),+];
 
-            if let Some(ref mut bytes) = bytes {
-                L2.handle_outgoing_frame(bytes);
-                L1.handle_outgoing_frame(bytes);
+            async fn initialize(stream: &mut Transport, init: &Init) -> Result<Self, String> {
+                Ok(($($layer::initialize(stream, init).await?,)+))
             }
 
-            return bytes;
-        }
-        None
-    }
-
-    fn process_incoming_frame(&mut self, frame: &mut bytes::BytesMut) -> Vec<crate::Command> {
-        let (L1, L2, L3) = self;
-        let mut cmds = Vec::new();
-        let mut frame_ref = frame;
-
-        if let Some(cmd) = L3.handle_incoming_frame(frame_ref) {
-            cmds.push(cmd);
-        }
+            fn nickname(&self) -> Option<&str> {
+                let ($($layer,)+) = self;
+                None$(.or_else(|| $layer.nickname()))+
+            }
 
-        if let Some(cmd) = L2.handle_incoming_frame(frame_ref) {
-            cmds.push(cmd);
-        }
+            fn process_cmd(
+                &mut self,
+                cmd: Box<dyn Any + Send>,
+            ) -> Result<Vec<BytesMut>, FailureReason> {
+                let ($($layer,)+) = self;
+                process_cmd!(cmd; []; $($layer),+);
+                Err(FailureReason::Unhandled)
+            }
 
-        if let Some(cmd) = L1.handle_incoming_frame(frame_ref) {
-            cmds.push(cmd);
+            fn process_incoming_frame(
+                &mut self,
+                frame: &mut BytesMut,
+            ) -> Result<Processed, Dropped> {
+                let ($($layer,)+) = self;
+                let mut processed = Processed::default();
+                process_incoming_frame!(frame, processed; []; $($layer),+);
+                Ok(processed)
+            }
         }
-        cmds
-    }
+    };
 }
+
+impl_controller!(L1);
+impl_controller!(L1, L2);
+impl_controller!(L1, L2, L3);
+impl_controller!(L1, L2, L3, L4);
+impl_controller!(L1, L2, L3, L4, L5);
+impl_controller!(L1, L2, L3, L4, L5, L6);
+impl_controller!(L1, L2, L3, L4, L5, L6, L7);
+impl_controller!(L1, L2, L3, L4, L5, L6, L7, L8);
+impl_controller!(L1, L2, L3, L4, L5, L6, L7, L8, L9);
+impl_controller!(L1, L2, L3, L4, L5, L6, L7, L8, L9, L10);
+impl_controller!(L1, L2, L3, L4, L5, L6, L7, L8, L9, L10, L11);
+impl_controller!(L1, L2, L3, L4, L5, L6, L7, L8, L9, L10, L11, L12);
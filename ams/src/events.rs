@@ -0,0 +1,157 @@
+//! The channel carrying the events emitted by an AMS instance to the consumer of [crate::Ams::next_event].
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+
+use crate::Event;
+
+/// Creates a channel buffering at most `capacity` events that may be dropped, see [EventSender::send].
+pub(crate) fn channel(capacity: usize) -> (EventSender, EventReceiver) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        capacity,
+        buffered: AtomicUsize::new(0),
+        dropped: AtomicU64::new(0),
+    });
+    (
+        EventSender {
+            tx,
+            shared: shared.clone(),
+        },
+        EventReceiver { rx, shared },
+    )
+}
+
+/// The state shared by both ends of the channel.
+struct Shared {
+    /// The number of buffered events at which events that may be dropped are dropped.
+    capacity: usize,
+    /// The number of events sent but not yet received.
+    buffered: AtomicUsize,
+    /// The number of events dropped since the channel was created.
+    dropped: AtomicU64,
+}
+
+/// Emits events without waiting on the consumer, so a consumer that stops draining them cannot stall the instance.
+#[derive(Clone)]
+pub(crate) struct EventSender {
+    tx: mpsc::UnboundedSender<Event>,
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    /// Emits the event, returning whether it was.
+    ///
+    /// Once the buffer is full, events reporting the outcome of something the application or a peer started, such as a
+    /// connection or a sent message, are still emitted so the application never waits on them forever. Any other event
+    /// is dropped and counted.
+    pub fn send(&self, event: Event) -> bool {
+        if event.droppable() && self.shared.buffered.load(Ordering::Relaxed) >= self.shared.capacity
+        {
+            let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            // Only log a few of the drops, as a consumer that stopped draining events may see a great many of them.
+            if dropped.is_power_of_two() {
+                tracing::warn!(dropped, "event buffer full, dropping events");
+            }
+            return false;
+        }
+        self.shared.buffered.fetch_add(1, Ordering::Relaxed);
+        if self.tx.send(event).is_err() {
+            self.shared.buffered.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Returns the number of events dropped since the channel was created.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Receives the events emitted by an [EventSender].
+pub(crate) struct EventReceiver {
+    rx: mpsc::UnboundedReceiver<Event>,
+    shared: Arc<Shared>,
+}
+
+impl Stream for EventReceiver {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let event = std::task::ready!(self.rx.poll_recv(cx));
+        if event.is_some() {
+            self.shared.buffered.fetch_sub(1, Ordering::Relaxed);
+        }
+        Poll::Ready(event)
+    }
+}
+
+impl Event {
+    /// Returns whether the event may be dropped when the consumer falls behind.
+    fn droppable(&self) -> bool {
+        match self {
+            Self::ConnectionRequested { .. }
+            | Self::ConnectionEstablished { .. }
+            | Self::ConnectionRejected { .. }
+            | Self::ConnectionDisconnected { .. }
+            | Self::MessageSent { .. }
+            | Self::MessageFailed { .. }
+            | Self::TransferComplete { .. }
+            | Self::TransferFailed { .. }
+            | Self::FileOffered { .. }
+            | Self::FileReceived { .. } => false,
+            Self::MessageReceived { .. }
+            | Self::LayerDropped { .. }
+            | Self::FramesLost { .. }
+            | Self::PresenceUpdate { .. }
+            | Self::PeerTyping { .. }
+            | Self::Throughput { .. }
+            | Self::Reaction { .. }
+            | Self::TransferProgress { .. }
+            | Self::RoomMessage { .. } => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    const PEER: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 1);
+
+    #[tokio::test]
+    async fn droppable_events_are_dropped_while_the_consumer_is_stalled() {
+        let (tx, mut rx) = channel(2);
+        for _ in 0..4 {
+            tx.send(Event::PeerTyping { peer: PEER });
+        }
+        // Lifecycle events are never dropped.
+        assert!(tx.send(Event::ConnectionRejected { peer: PEER }));
+        assert_eq!(tx.dropped(), 2);
+
+        assert!(matches!(rx.next().await, Some(Event::PeerTyping { .. })));
+        assert!(matches!(rx.next().await, Some(Event::PeerTyping { .. })));
+        assert!(matches!(
+            rx.next().await,
+            Some(Event::ConnectionRejected { .. })
+        ));
+
+        // The consumer caught up, so events are emitted again.
+        assert!(tx.send(Event::PeerTyping { peer: PEER }));
+        assert!(matches!(rx.next().await, Some(Event::PeerTyping { .. })));
+    }
+}
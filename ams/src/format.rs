@@ -0,0 +1,211 @@
+//! The serialization formats that messages are encoded with on the wire.
+//!
+//! The format is selected with [crate::AmsConfig::format] and used by the [crate::layers::transmit::Transmit] layer.
+//! Formats are not negotiated, so both peers of a connection must be configured with the same one. Each peer
+//! identifies its format when connecting, and the connection is rejected with [crate::Event::ConnectionRejected] if the
+//! two differ, instead of the frames of one being misread by the other.
+//!
+//! The formats trade frame size against how easily other implementations can read them:
+//!
+//! - [Postcard] produces the smallest frames, but is only widely implemented in Rust.
+//! - `Json`, enabled by the `json` feature, produces the largest frames, but is readable by humans and by virtually
+//!   any language.
+//! - `MessagePack`, enabled by the `msgpack` feature, produces compact frames, if somewhat larger than postcard's as
+//!   field names are included, and has implementations in most languages.
+use bytes::BytesMut;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// The serialization format messages are exchanged with, see [crate::AmsConfig::format].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// The [Postcard] format.
+    #[default]
+    Postcard,
+    /// The [Json] format.
+    #[cfg(feature = "json")]
+    Json,
+    /// The [MessagePack] format.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl MessageFormat {
+    /// Returns the [Format::NAME] of the format.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Postcard => Postcard::NAME,
+            #[cfg(feature = "json")]
+            Self::Json => Json::NAME,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::NAME,
+        }
+    }
+
+    /// Returns the byte identifying the format when connecting to a remote peer.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Self::Postcard => 0,
+            #[cfg(feature = "json")]
+            Self::Json => 1,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => 2,
+        }
+    }
+
+    /// Serializes the value with this format, see [Format::serialize].
+    pub(crate) fn serialize<T: Serialize>(
+        self,
+        value: &T,
+        bytes: BytesMut,
+    ) -> Result<BytesMut, String> {
+        match self {
+            Self::Postcard => Postcard::serialize(value, bytes),
+            #[cfg(feature = "json")]
+            Self::Json => Json::serialize(value, bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::serialize(value, bytes),
+        }
+    }
+
+    /// Deserializes a value with this format, see [Format::deserialize].
+    pub(crate) fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Postcard => Postcard::deserialize(bytes),
+            #[cfg(feature = "json")]
+            Self::Json => Json::deserialize(bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::deserialize(bytes),
+        }
+    }
+}
+
+/// A serialization format for the values exchanged with remote peers.
+pub trait Format: Send + 'static {
+    /// The name of the format, for debugging.
+    const NAME: &'static str;
+
+    /// Serializes the value, appending it to the provided bytes.
+    fn serialize<T: Serialize>(value: &T, bytes: BytesMut) -> Result<BytesMut, String>;
+
+    /// Deserializes a value from the provided bytes.
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String>;
+}
+
+/// The compact binary [postcard](https://docs.rs/postcard) format, used by default.
+pub struct Postcard;
+
+impl Format for Postcard {
+    const NAME: &'static str = "postcard";
+
+    fn serialize<T: Serialize>(value: &T, bytes: BytesMut) -> Result<BytesMut, String> {
+        postcard::to_extend(value, bytes).map_err(|e| e.to_string())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        postcard::from_bytes(bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// The human readable JSON format, easier to debug and to interoperate with peers not written in Rust, at the cost of
+/// larger frames.
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Format for Json {
+    const NAME: &'static str = "json";
+
+    fn serialize<T: Serialize>(value: &T, bytes: BytesMut) -> Result<BytesMut, String> {
+        let mut writer = bytes::BufMut::writer(bytes);
+        serde_json::to_writer(&mut writer, value).map_err(|e| e.to_string())?;
+        Ok(writer.into_inner())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        serde_json::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// The compact binary [MessagePack](https://msgpack.org) format, a middle ground between [Postcard] and JSON that
+/// many languages can read.
+///
+/// Structs are encoded as maps keyed by field name, so peers are not tied to the field order of this crate.
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Format for MessagePack {
+    const NAME: &'static str = "msgpack";
+
+    fn serialize<T: Serialize>(value: &T, bytes: BytesMut) -> Result<BytesMut, String> {
+        let mut writer = bytes::BufMut::writer(bytes);
+        rmp_serde::encode::write_named(&mut writer, value).map_err(|e| e.to_string())?;
+        Ok(writer.into_inner())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Message;
+
+    /// Serializes a message with the `F` format, asserting that it deserializes back to the same message. Returns the
+    /// serialized message.
+    fn round_trip<F: Format>() -> BytesMut {
+        let message = Message {
+            id: 7,
+            payload: b"hello".to_vec(),
+            sender: "127.0.0.1:4000".to_string(),
+        };
+        let bytes = F::serialize(&message, BytesMut::new()).unwrap();
+        let decoded = F::deserialize::<Message>(&bytes).unwrap();
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.payload, message.payload);
+        assert_eq!(decoded.sender, message.sender);
+        bytes
+    }
+
+    #[test]
+    fn message_round_trips_through_postcard() {
+        round_trip::<Postcard>();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn message_round_trips_through_json() {
+        let bytes = round_trip::<Json>();
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            r#"{"id":7,"payload":[104,101,108,108,111],"sender":"127.0.0.1:4000"}"#
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn malformed_json_is_an_error() {
+        assert!(Json::deserialize::<Message>(b"{\"id\":7").is_err());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn message_round_trips_through_msgpack() {
+        let bytes = round_trip::<MessagePack>();
+        // Fields are keyed by name.
+        assert!(
+            bytes
+                .windows(b"payload".len())
+                .any(|window| window == b"payload")
+        );
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn malformed_msgpack_is_an_error() {
+        let bytes = round_trip::<MessagePack>();
+        assert!(MessagePack::deserialize::<Message>(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
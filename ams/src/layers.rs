@@ -1,25 +1,272 @@
+//! The building blocks of a [crate::controller::Controller] stack.
+//!
+//! Each layer is responsible for a single piece of functionality, such as serializing messages or encrypting frames.
+//! Layers are composed into a tuple to form a controller stack, where the first layer is closest to the wire. See
+//! [crate::controller::Controller] for how frames and commands flow through the stack.
+//!
+//! ## Implementing a custom layer
+//!
+//! A layer that drops any frame larger than a fixed size:
+//!
+//! ```
+//! use ams::{
+//!     layers::{Incoming, Init, Layer, transmit::Transmit},
+//!     transport::Transport,
+//! };
+//! use bytes::BytesMut;
+//!
+//! struct MaxSize;
+//!
+//! impl Layer for MaxSize {
+//!     const NAME: &'static str = "max-size";
+//!
+//!     // This layer does not accept any commands.
+//!     type Command = ();
+//!
+//!     async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+//!         Ok(Self)
+//!     }
+//!
+//!     fn handle_cmd(&mut self, _command: Self::Command) -> Option<BytesMut> {
+//!         None
+//!     }
+//!
+//!     fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+//!         if frame.len() > 1024 {
+//!             return Err(format!("frame of {} bytes is too large", frame.len()));
+//!         }
+//!         Ok(Incoming::Forward(None))
+//!     }
+//!
+//!     fn handle_outgoing_frame(&mut self, _frame: &mut BytesMut) {}
+//! }
+//!
+//! # async fn run() -> Result<(), ams::AmsError> {
+//! // Transmit must remain part of the stack for messages to be sent.
+//! let _ams = ams::Ams::bind_with_controller::<(MaxSize, Transmit)>("127.0.0.1:0", Default::default()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Control layers
+//!
+//! Layers such as [ping::Ping] or [react::React] exchange frames of their own with the remote peer, alongside the
+//! frames of the layers above them. Each frame such a control layer passes on is prefixed with a tag byte: zero for a
+//! frame of the layers above it, which is passed on once the tag is removed, and another value for each kind of frame
+//! of its own.
+pub mod auth;
+pub mod file;
+pub mod fragment;
+pub mod goodbye;
+pub mod identity;
+pub mod integrity;
+pub mod ping;
+pub mod presence;
+pub mod ratelimit;
+pub mod react;
+pub mod room;
+pub mod sequence;
+pub mod server;
 pub mod transmit;
+pub mod typing;
 
-use bytes::BytesMut;
-use tokio::net::TcpStream;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use std::net::SocketAddr;
 
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::{
+    AmsConfig, DisconnectReason, FailureReason,
+    api::{Message, Presence, Reaction, Room, Transfer},
+    format::MessageFormat,
+    transport::{Side, Transport},
+};
+
+/// A single layer of a [crate::controller::Controller] stack.
 pub trait Layer: Send + 'static {
+    /// A unique identifier for the layer, used when reporting layer-level events such as dropped frames.
+    const NAME: &'static str;
+
+    /// The commands this layer handles via [Self::handle_cmd].
     type Command: Send + 'static;
 
-    /// Initializes the layer.
+    /// Initializes the layer in the context of the connection it belongs to.
+    ///
+    /// The stream may be used to exchange frames with the remote peer before any command or frame is processed, such
+    /// as to authenticate it. Returns an error with the reason if the layer could not be initialized, in which case the
+    /// connection is rejected with [crate::Event::ConnectionRejected].
     fn initialize(
-        stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
-    ) -> impl std::future::Future<Output = Self> + std::marker::Send;
+        stream: &mut Transport,
+        init: &Init,
+    ) -> impl std::future::Future<Output = Result<Self, String>> + std::marker::Send
+    where
+        Self: Sized;
+
+    /// Returns the nickname the remote peer introduced itself with while this layer was initialized, reported in
+    /// [crate::Event::ConnectionEstablished].
+    ///
+    /// By default, the layer exchanged no nickname.
+    fn nickname(&self) -> Option<&str> {
+        None
+    }
 
     /// handles a command sent to this layer.
     fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut>;
 
     /// Manipulates an incoming frame sent from the remote peer.
     ///
-    /// Returns a ManagerCmd if the frame results in an action required by the AMS manager.
-    fn handle_incoming_frame(&mut self, frame: &mut bytes::BytesMut) -> Option<crate::Command>;
+    /// Returns what should happen to the frame next, see [Incoming]. Returns an error with the reason if the layer
+    /// discarded the frame, in which case the frame is not passed to any further layers.
+    fn handle_incoming_frame(&mut self, frame: &mut bytes::BytesMut) -> Result<Incoming, String>;
 
     /// Manipulates an outgoing frame before it is sent to the remote peer.
     fn handle_outgoing_frame(&mut self, frame: &mut bytes::BytesMut);
+
+    /// Splits an outgoing frame, once manipulated by [Self::handle_outgoing_frame], into the frames passed on to the
+    /// layers closer to the wire. Returns the reason the frame cannot be sent if it cannot be split, in which case the
+    /// message it carries is reported with [crate::Event::MessageFailed].
+    ///
+    /// By default, the frame is passed on as is.
+    fn split_outgoing_frame(&mut self, frame: BytesMut) -> Result<Vec<BytesMut>, FailureReason> {
+        Ok(vec![frame])
+    }
+}
+
+/// The context a controller stack is initialized in, provided to [Layer::initialize].
+#[derive(Debug, Clone)]
+pub struct Init {
+    side: Side,
+    nickname: Option<String>,
+    token: Option<auth::Token>,
+    format: MessageFormat,
+    fragment_limits: fragment::FragmentLimits,
+}
+
+impl Init {
+    /// Creates the context of a connection on the specified side, made by an instance with the provided configuration.
+    pub fn new(side: Side, config: &AmsConfig) -> Self {
+        Self {
+            side,
+            nickname: config.nickname.clone(),
+            token: config.auth_token.clone(),
+            format: config.format,
+            fragment_limits: config.fragment_limits,
+        }
+    }
+
+    /// Returns which side of the connection the local peer is on.
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    /// Returns the [AmsConfig::nickname] the local peer introduces itself with, if any.
+    pub fn nickname(&self) -> Option<&str> {
+        self.nickname.as_deref()
+    }
+
+    /// Returns the [AmsConfig::auth_token] the local peer authenticates with, if any.
+    pub fn token(&self) -> Option<&auth::Token> {
+        self.token.as_ref()
+    }
+
+    /// Returns the [AmsConfig::format] messages are exchanged with.
+    pub fn format(&self) -> MessageFormat {
+        self.format
+    }
+
+    /// Returns the [AmsConfig::fragment_limits] of the fragments sent and received.
+    pub fn fragment_limits(&self) -> fragment::FragmentLimits {
+        self.fragment_limits
+    }
+}
+
+/// What should happen to an incoming frame once a layer has processed it.
+pub enum Incoming {
+    /// Pass the (possibly modified) frame on to the next layer, optionally signaling the AMS manager.
+    Forward(Option<Signal>),
+    /// The frame was fully handled by this layer and is not passed on, optionally signaling the AMS manager.
+    Handled(Option<Signal>),
+    /// The frame was fully handled by this layer and is not passed on. The provided frame is sent back to the remote
+    /// peer, passing through the layers closer to the wire as any other outgoing frame from this layer would.
+    Reply(BytesMut),
+}
+
+/// An action required by the AMS manager as a result of a layer processing an incoming frame.
+pub enum Signal {
+    /// Disconnect from the remote peer for the given reason.
+    Disconnect(DisconnectReason),
+    /// Deliver the message to another connected peer on behalf of the remote peer.
+    Relay {
+        /// The address of the peer to deliver the message to.
+        recipient: SocketAddr,
+        /// The message to deliver.
+        message: Message,
+    },
+    /// Frames sent by the remote peer were lost before reaching this layer.
+    FramesLost {
+        /// The number of frames lost.
+        count: u64,
+    },
+    /// The remote peer sent a message.
+    Message(Message),
+    /// The remote peer announced its presence.
+    Presence(Presence),
+    /// The remote peer is typing.
+    Typing,
+    /// The remote peer joined or left a room.
+    RoomMembership {
+        /// The name of the room.
+        room: String,
+        /// Whether the remote peer joined the room, rather than left it.
+        joined: bool,
+    },
+    /// The remote peer sent a message to a room.
+    RoomMessage(Room),
+    /// The remote peer reacted to a message.
+    Reaction(Reaction),
+    /// The remote peer sent a part of a file transfer.
+    Transfer(Transfer),
+}
+
+/// The tag of a frame a control layer passes on from the layers above it, see [the module documentation](self).
+pub(crate) const DATA: u8 = 0;
+
+/// Returns an empty control frame prefixed with the specified tag.
+pub(crate) fn tagged(tag: u8) -> BytesMut {
+    let mut frame = BytesMut::new();
+    frame.put_u8(tag);
+    frame
+}
+
+/// Prefixes a frame from the layers above a control layer with the [DATA] tag.
+pub(crate) fn tag_data(frame: &mut BytesMut) {
+    let mut tagged = BytesMut::with_capacity(frame.len() + 1);
+    tagged.put_u8(DATA);
+    tagged.extend_from_slice(frame);
+    *frame = tagged;
+}
+
+/// Removes the tag of an incoming frame of a control layer, returning it.
+pub(crate) fn untag(frame: &mut BytesMut) -> Result<u8, String> {
+    if frame.is_empty() {
+        return Err("empty frame".to_string());
+    }
+    Ok(frame.get_u8())
+}
+
+/// A frame that was discarded by a layer while being processed.
+pub struct Dropped {
+    /// The [Layer::NAME] of the layer that discarded the frame.
+    pub layer: &'static str,
+    /// The reason the frame was discarded.
+    pub reason: String,
+}
+
+impl Dropped {
+    /// Creates a new [Dropped] for the given layer.
+    pub fn new<L: Layer>(reason: String) -> Self {
+        Self {
+            layer: L::NAME,
+            reason,
+        }
+    }
 }
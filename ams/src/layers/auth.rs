@@ -0,0 +1,230 @@
+//! A controller layer for authenticating the remote peer with a pre-shared token.
+use bytes::{Bytes, BytesMut};
+use futures_util::sink::SinkExt;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use tokio_stream::StreamExt;
+
+use super::{Incoming, Init};
+use crate::transport::{Side, Transport};
+
+/// The length of the challenge each peer sends.
+const NONCE_LEN: usize = 32;
+
+/// A token shared by the peers allowed to connect to each other, configured with [crate::AmsConfig::auth_token].
+///
+/// The token is never sent to the remote peer, and is redacted from its [Debug] output so it is never logged.
+#[derive(Clone)]
+pub struct Token(Vec<u8>);
+
+impl Token {
+    /// Creates a token from its bytes.
+    pub fn new(token: impl Into<Vec<u8>>) -> Self {
+        Self(token.into())
+    }
+
+    /// Returns the bytes of the token.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+/// A Controller layer that only lets a connection be established if the remote peer knows the
+/// [crate::AmsConfig::auth_token], included in every built-in stack.
+///
+/// While initializing, each peer sends a random challenge and answers the other's with an HMAC-SHA256 of both
+/// challenges and of the side of the connection it is on, keyed by the token. Including the side keeps an attacker
+/// from relaying the local peer's answer on one connection as its own answer on another. If the remote peer's answer
+/// is wrong, or it does not answer within the [crate::AmsConfig::handshake_timeout], the connection is rejected with
+/// [crate::Event::ConnectionRejected]. A peer without a token sends an empty challenge instead, so two peers without a
+/// token connect as usual, while a peer with a token rejects one without. Once authenticated, frames pass through this
+/// layer untouched.
+///
+/// The token is not used to encrypt or sign frames, so this layer does not protect against an attacker able to
+/// tamper with the stream; combine it with TLS for that.
+pub struct Auth;
+
+impl super::Layer for Auth {
+    const NAME: &'static str = "auth";
+
+    // This layer does not accept any commands.
+    type Command = ();
+
+    async fn initialize(stream: &mut Transport, init: &Init) -> Result<Self, String> {
+        authenticate(stream, init.token().map(Token::as_bytes), init.side()).await?;
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<BytesMut> {
+        None
+    }
+
+    fn handle_outgoing_frame(&mut self, _frame: &mut BytesMut) {}
+
+    fn handle_incoming_frame(&mut self, _frame: &mut BytesMut) -> Result<Incoming, String> {
+        Ok(Incoming::Forward(None))
+    }
+}
+
+/// Proves to the remote peer that the local peer, on the specified side of the connection, knows the token, and
+/// verifies that the remote peer does too. Succeeds without proving anything if neither peer has a token.
+async fn authenticate(
+    stream: &mut Transport,
+    token: Option<&[u8]>,
+    side: Side,
+) -> Result<(), String> {
+    let mut local = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut local);
+    let challenge: &[u8] = if token.is_some() { &local } else { &[] };
+    send(stream, challenge).await?;
+    let remote = receive(stream).await?;
+    let token = match (token, remote.is_empty()) {
+        (None, true) => return Ok(()),
+        (None, false) => return Err("the remote peer requires a token".to_string()),
+        (Some(_), true) => return Err("the remote peer has no token".to_string()),
+        (Some(token), false) => token,
+    };
+    if remote.len() != NONCE_LEN {
+        return Err(format!(
+            "challenge of {} bytes, expected {NONCE_LEN}",
+            remote.len()
+        ));
+    }
+
+    send(
+        stream,
+        &answer(token, side, &remote, &local).finalize().into_bytes(),
+    )
+    .await?;
+    let remote_answer = receive(stream).await?;
+    answer(token, side.opposite(), &local, &remote)
+        .verify_slice(&remote_answer)
+        .map_err(|_| "the remote peer does not know the token".to_string())
+}
+
+/// Returns the answer to the challenge, given the side of the connection and the nonce of the answering peer.
+fn answer(token: &[u8], side: Side, challenge: &[u8], nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token).expect("HMAC accepts keys of any length");
+    mac.update(match side {
+        Side::Inbound => b"inbound",
+        Side::Outbound => b"outbound",
+    });
+    mac.update(challenge);
+    mac.update(nonce);
+    mac
+}
+
+/// Sends a frame to the remote peer.
+async fn send(stream: &mut Transport, frame: &[u8]) -> Result<(), String> {
+    stream
+        .send(Bytes::copy_from_slice(frame))
+        .await
+        .map_err(|e| format!("failed to send frame: {e}"))
+}
+
+/// Receives a frame from the remote peer.
+async fn receive(stream: &mut Transport) -> Result<BytesMut, String> {
+    match stream.next().await {
+        Some(Ok(frame)) => Ok(frame),
+        Some(Err(e)) => Err(format!("failed to read frame: {e}")),
+        None => Err("the remote peer closed the connection".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AmsConfig, layers::Layer};
+
+    /// Initializes an [Auth] layer on each end of an in-memory stream, with the specified tokens.
+    async fn authenticate_with(
+        local: Option<&str>,
+        remote: Option<&str>,
+    ) -> (Result<Auth, String>, Result<Auth, String>) {
+        let init = |side, token: Option<&str>| {
+            let config = AmsConfig {
+                auth_token: token.map(Token::new),
+                ..Default::default()
+            };
+            Init::new(side, &config)
+        };
+        let (outbound, inbound) = (init(Side::Outbound, local), init(Side::Inbound, remote));
+        let (mut local, mut remote) = crate::transport::duplex();
+        tokio::join!(
+            Auth::initialize(&mut local, &outbound),
+            Auth::initialize(&mut remote, &inbound)
+        )
+    }
+
+    #[tokio::test]
+    async fn peers_sharing_the_token_are_authenticated() {
+        let (local, remote) = authenticate_with(Some("shared"), Some("shared")).await;
+        assert!(local.is_ok());
+        assert!(remote.is_ok());
+
+        let (local, remote) = authenticate_with(None, None).await;
+        assert!(local.is_ok());
+        assert!(remote.is_ok());
+    }
+
+    #[tokio::test]
+    async fn peers_with_different_tokens_are_rejected() {
+        let (local, remote) = authenticate_with(Some("shared"), Some("other")).await;
+        assert_eq!(
+            local.err().as_deref(),
+            Some("the remote peer does not know the token")
+        );
+        assert_eq!(
+            remote.err().as_deref(),
+            Some("the remote peer does not know the token")
+        );
+
+        let (local, remote) = authenticate_with(Some("shared"), None).await;
+        assert_eq!(local.err().as_deref(), Some("the remote peer has no token"));
+        assert_eq!(
+            remote.err().as_deref(),
+            Some("the remote peer requires a token")
+        );
+    }
+
+    #[tokio::test]
+    async fn answer_relayed_from_another_connection_is_rejected() {
+        let config = AmsConfig::builder().auth_token("shared").build();
+        let init = Init::new(Side::Inbound, &config);
+        // The attacker, not knowing the token, opens two connections to the same peer.
+        let (mut first, mut first_victim) = crate::transport::duplex();
+        let (mut second, mut second_victim) = crate::transport::duplex();
+        let victim = async {
+            tokio::join!(
+                Auth::initialize(&mut first_victim, &init),
+                Auth::initialize(&mut second_victim, &init)
+            )
+        };
+        let attacker = async {
+            let first_challenge = receive(&mut first).await.unwrap();
+            let second_challenge = receive(&mut second).await.unwrap();
+            // The peer's own challenge on the first connection is sent as the challenge on the second, and the nonce of
+            // the second as the challenge on the first, so the answer on the second connection is computed over the
+            // same challenges as the answer expected on the first.
+            send(&mut second, &first_challenge).await.unwrap();
+            send(&mut first, &second_challenge).await.unwrap();
+            let relayed = receive(&mut second).await.unwrap();
+            receive(&mut first).await.unwrap();
+            send(&mut first, &relayed).await.unwrap();
+            // The second connection is never answered.
+            drop(second);
+        };
+        let ((first, _), ()) = tokio::join!(victim, attacker);
+        assert_eq!(
+            first.err().as_deref(),
+            Some("the remote peer does not know the token")
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! A controller layer for transferring files.
+use bytes::BytesMut;
+
+use super::{DATA, Incoming, Init, Signal};
+use crate::{api::Transfer, transport::Transport};
+
+/// Tags a part of a file transfer.
+const TRANSFER: u8 = 1;
+
+/// A [control layer](super#control-layers) that carries the files sent with [crate::Ams::send_file], alongside the
+/// frames of the layers above this one.
+///
+/// Received parts are signaled to the manager with [Signal::Transfer], which writes the file to a temporary directory,
+/// verifies its checksum and reports it with [crate::Event::FileReceived].
+pub struct File;
+
+impl super::Layer for File {
+    const NAME: &'static str = "file";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        match command {
+            Cmd::Send(transfer) => match postcard::to_extend(&transfer, super::tagged(TRANSFER)) {
+                Ok(bytes) => Some(bytes),
+                Err(err) => {
+                    tracing::debug!(%err, "failed to encode transfer");
+                    None
+                }
+            },
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            TRANSFER => {
+                let transfer = postcard::from_bytes::<Transfer>(frame)
+                    .map_err(|e| format!("failed to decode transfer: {e}"))?;
+                Ok(Incoming::Handled(Some(Signal::Transfer(transfer))))
+            }
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// The commands handled by the [File] layer.
+pub enum Cmd {
+    /// Serializes the part of a transfer into a frame to be sent to the remote peer.
+    Send(Transfer),
+}
@@ -0,0 +1,410 @@
+//! A controller layer for splitting large frames into fragments and reassembling them.
+use std::{collections::HashMap, time::Duration};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::time::Instant;
+
+use super::{Incoming, Init};
+use crate::{FailureReason, transport::Transport};
+
+/// Tags a frame from the layers above this one that was sent as is.
+const WHOLE: u8 = 0;
+/// Tags a fragment of a frame from the layers above this one.
+const FRAGMENT: u8 = 1;
+/// The length of a fragment header, following the tag: the message id, fragment index and fragment count.
+const HEADER_LEN: usize = 12;
+/// How long a partially received message is kept after its last fragment arrived.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The limits of a [Fragment] layer, configured with [crate::AmsConfig::fragment_limits].
+///
+/// They bound the memory a remote peer can make the layer hold. Both peers must use the same limits, as a message
+/// split according to the sender's limits is refused if it exceeds the receiver's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentLimits {
+    /// The largest fragment, in bytes. Larger frames are split into fragments of this length.
+    pub max_len: usize,
+    /// The number of fragments a message may be split into.
+    pub max_fragments: u32,
+    /// The number of fragments of incomplete messages buffered at once.
+    pub max_in_flight: u32,
+    /// The number of bytes of incomplete messages buffered at once.
+    pub max_buffered: usize,
+}
+
+impl Default for FragmentLimits {
+    /// Fragments of up to 64 KiB, at most 1024 per message, and at most 4096 fragments totalling 64 MiB buffered.
+    fn default() -> Self {
+        Self {
+            max_len: 64 * 1024,
+            max_fragments: 1024,
+            max_in_flight: 4096,
+            max_buffered: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl FragmentLimits {
+    /// Returns an error if a frame cannot be split with these limits.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.max_len == 0 || self.max_fragments == 0 {
+            return Err("fragment length and count must not be zero".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A Controller layer that splits frames larger than the [FragmentLimits::max_len] into fragments, and reassembles
+/// the fragments received from the remote peer before passing the frame on.
+///
+/// Every frame is prefixed with a tag byte, distinguishing fragments from frames sent as is. Each fragment carries the
+/// id of the message it belongs to, its index and the total fragment count, so fragments of different messages may
+/// be interleaved. Fragments of a message must arrive in order.
+///
+/// Outgoing frames that would need more than [FragmentLimits::max_fragments] fragments are not sent, and their message
+/// is reported with [crate::FailureReason::TooManyFragments]. An incoming message exceeding any of the
+/// [FragmentLimits] is discarded instead, as is a message whose next fragment does not arrive within 30 seconds. The
+/// remote peer is not told: only the local peer reports it, with [crate::Event::LayerDropped] naming this layer.
+pub struct Fragment {
+    /// The limits of the fragments sent and received.
+    limits: FragmentLimits,
+    /// The id of the next message split into fragments.
+    next_id: u32,
+    /// The partially received messages, keyed by id.
+    partial: HashMap<u32, Partial>,
+    /// The number of fragments buffered in [Self::partial].
+    in_flight: u32,
+    /// The number of bytes buffered in [Self::partial].
+    buffered: usize,
+}
+
+/// A partially received message.
+struct Partial {
+    /// The number of fragments the message was split into.
+    count: u32,
+    /// The number of fragments received so far.
+    received: u32,
+    /// The payload of the fragments received so far.
+    payload: BytesMut,
+    /// When the last fragment was received.
+    updated: Instant,
+}
+
+impl Fragment {
+    /// Creates a layer enforcing the specified limits.
+    fn new(limits: FragmentLimits) -> Self {
+        Self {
+            limits,
+            next_id: 0,
+            partial: HashMap::new(),
+            in_flight: 0,
+            buffered: 0,
+        }
+    }
+
+    /// Discards the partially received messages whose next fragment is overdue.
+    fn discard_expired(&mut self) {
+        let (in_flight, buffered) = (&mut self.in_flight, &mut self.buffered);
+        self.partial.retain(|_, partial| {
+            let keep = partial.updated.elapsed() < REASSEMBLY_TIMEOUT;
+            if !keep {
+                *in_flight -= partial.received;
+                *buffered -= partial.payload.len();
+            }
+            keep
+        });
+    }
+
+    /// Discards the partially received message with the specified id.
+    fn discard(&mut self, id: u32) {
+        if let Some(partial) = self.partial.remove(&id) {
+            self.in_flight -= partial.received;
+            self.buffered -= partial.payload.len();
+        }
+    }
+
+    /// Buffers a fragment, returning the reassembled frame once every fragment of its message was received.
+    fn reassemble(
+        &mut self,
+        id: u32,
+        index: u32,
+        count: u32,
+        chunk: &[u8],
+    ) -> Result<Option<BytesMut>, String> {
+        let FragmentLimits {
+            max_len,
+            max_fragments,
+            max_in_flight,
+            max_buffered,
+        } = self.limits;
+        if count == 0 || count > max_fragments {
+            self.discard(id);
+            return Err(format!(
+                "message {id} announces {count} fragments, at most {max_fragments} are allowed"
+            ));
+        }
+        if chunk.len() > max_len {
+            self.discard(id);
+            return Err(format!(
+                "fragment {index} of message {id} is {} bytes, at most {max_len} are allowed",
+                chunk.len()
+            ));
+        }
+        if self.in_flight >= max_in_flight {
+            self.discard(id);
+            return Err(format!("more than {max_in_flight} fragments in flight"));
+        }
+        if self.buffered + chunk.len() > max_buffered {
+            self.discard(id);
+            return Err(format!("more than {max_buffered} bytes in flight"));
+        }
+
+        if index == 0 {
+            if self.partial.contains_key(&id) {
+                self.discard(id);
+                return Err(format!("message {id} was restarted before being completed"));
+            }
+            self.partial.insert(
+                id,
+                Partial {
+                    count,
+                    received: 0,
+                    payload: BytesMut::new(),
+                    updated: Instant::now(),
+                },
+            );
+        }
+        let Some(partial) = self.partial.get_mut(&id) else {
+            return Err(format!("fragment {index} of unknown message {id}"));
+        };
+        if partial.count != count || partial.received != index {
+            self.discard(id);
+            return Err(format!("fragment {index} of message {id} is out of order"));
+        }
+
+        partial.payload.extend_from_slice(chunk);
+        partial.received += 1;
+        partial.updated = Instant::now();
+        self.in_flight += 1;
+        self.buffered += chunk.len();
+
+        if partial.received < count {
+            return Ok(None);
+        }
+        let partial = self
+            .partial
+            .remove(&id)
+            .expect("partial message was just updated");
+        self.in_flight -= partial.received;
+        self.buffered -= partial.payload.len();
+        Ok(Some(partial.payload))
+    }
+}
+
+impl super::Layer for Fragment {
+    const NAME: &'static str = "fragment";
+
+    // This layer does not accept any commands.
+    type Command = ();
+
+    async fn initialize(_stream: &mut Transport, init: &Init) -> Result<Self, String> {
+        Ok(Self::new(init.fragment_limits()))
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<BytesMut> {
+        None
+    }
+
+    fn handle_outgoing_frame(&mut self, _frame: &mut BytesMut) {}
+
+    fn split_outgoing_frame(&mut self, frame: BytesMut) -> Result<Vec<BytesMut>, FailureReason> {
+        let FragmentLimits {
+            max_len,
+            max_fragments,
+            ..
+        } = self.limits;
+        if frame.len() <= max_len {
+            let mut tagged = BytesMut::with_capacity(frame.len() + 1);
+            tagged.put_u8(WHOLE);
+            tagged.extend_from_slice(&frame);
+            return Ok(vec![tagged]);
+        }
+
+        let count = frame.len().div_ceil(max_len);
+        if count > max_fragments as usize {
+            tracing::debug!(
+                bytes = frame.len(),
+                "frame too large to be split into fragments"
+            );
+            return Err(FailureReason::TooManyFragments);
+        }
+        let count = count as u32;
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(frame
+            .chunks(max_len)
+            .zip(0..count)
+            .map(|(chunk, index)| {
+                let mut fragment = BytesMut::with_capacity(1 + HEADER_LEN + chunk.len());
+                fragment.put_u8(FRAGMENT);
+                fragment.put_u32(id);
+                fragment.put_u32(index);
+                fragment.put_u32(count);
+                fragment.extend_from_slice(chunk);
+                fragment
+            })
+            .collect())
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        if frame.is_empty() {
+            return Err("empty frame".to_string());
+        }
+        match frame.get_u8() {
+            WHOLE => Ok(Incoming::Forward(None)),
+            FRAGMENT => {
+                if frame.len() < HEADER_LEN {
+                    return Err("truncated fragment header".to_string());
+                }
+                let id = frame.get_u32();
+                let index = frame.get_u32();
+                let count = frame.get_u32();
+
+                self.discard_expired();
+                match self.reassemble(id, index, count, frame)? {
+                    Some(payload) => {
+                        *frame = payload;
+                        Ok(Incoming::Forward(None))
+                    }
+                    None => Ok(Incoming::Handled(None)),
+                }
+            }
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::Layer;
+
+    /// A layer with small limits: fragments of at most 16 bytes, at most 4 per message, 8 in flight and 48 bytes
+    /// buffered.
+    fn small() -> Fragment {
+        Fragment::new(FragmentLimits {
+            max_len: 16,
+            max_fragments: 4,
+            max_in_flight: 8,
+            max_buffered: 48,
+        })
+    }
+
+    /// Returns a fragment of the message with the specified id.
+    fn fragment(id: u32, index: u32, count: u32, chunk: &[u8]) -> BytesMut {
+        let mut fragment = BytesMut::new();
+        fragment.put_u8(FRAGMENT);
+        fragment.put_u32(id);
+        fragment.put_u32(index);
+        fragment.put_u32(count);
+        fragment.extend_from_slice(chunk);
+        fragment
+    }
+
+    /// Passes an incoming frame through the layer, returning the frame if it was reassembled.
+    fn receive(layer: &mut Fragment, mut frame: BytesMut) -> Result<Option<BytesMut>, String> {
+        match layer.handle_incoming_frame(&mut frame)? {
+            Incoming::Forward(_) => Ok(Some(frame)),
+            _ => Ok(None),
+        }
+    }
+
+    #[test]
+    fn interleaved_fragments_are_reassembled() {
+        let mut sender = small();
+        let first = sender
+            .split_outgoing_frame(BytesMut::from(&[1; 40][..]))
+            .unwrap();
+        let second = sender
+            .split_outgoing_frame(BytesMut::from(&[2; 20][..]))
+            .unwrap();
+        assert_eq!((first.len(), second.len()), (3, 2));
+
+        let mut receiver = small();
+        let mut reassembled = Vec::new();
+        for frame in [&first[0], &second[0], &second[1], &first[1], &first[2]] {
+            reassembled.extend(receive(&mut receiver, frame.clone()).unwrap());
+        }
+        assert_eq!(reassembled, [&[2; 20][..], &[1; 40][..]]);
+        assert_eq!((receiver.in_flight, receiver.buffered), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn limits_are_taken_from_the_config() {
+        let config = crate::AmsConfig::builder()
+            .fragment_limits(FragmentLimits {
+                max_len: 16,
+                ..Default::default()
+            })
+            .build();
+        let init = Init::new(crate::transport::Side::Outbound, &config);
+        let (mut stream, _remote) = crate::transport::duplex();
+        let mut layer = Fragment::initialize(&mut stream, &init).await.unwrap();
+        let fragments = layer
+            .split_outgoing_frame(BytesMut::from(&[0; 40][..]))
+            .unwrap();
+        assert_eq!(fragments.len(), 3);
+    }
+
+    #[test]
+    fn frame_needing_too_many_fragments_is_not_sent() {
+        let result = small().split_outgoing_frame(BytesMut::from(&[0; 65][..]));
+        assert_eq!(result, Err(FailureReason::TooManyFragments));
+    }
+
+    #[test]
+    fn over_limit_fragment_count_is_refused_before_buffering() {
+        let mut layer = small();
+        assert!(receive(&mut layer, fragment(0, 0, u32::MAX, &[0; 16])).is_err());
+        assert!(layer.partial.is_empty());
+        assert_eq!((layer.in_flight, layer.buffered), (0, 0));
+    }
+
+    #[test]
+    fn oversized_fragment_is_refused() {
+        let mut layer = small();
+        assert!(
+            receive(&mut layer, fragment(0, 0, 2, &[0; 16]))
+                .unwrap()
+                .is_none()
+        );
+        assert!(receive(&mut layer, fragment(0, 1, 2, &[0; 17])).is_err());
+        assert!(layer.partial.is_empty());
+        assert_eq!((layer.in_flight, layer.buffered), (0, 0));
+    }
+
+    #[test]
+    fn buffered_bytes_are_limited() {
+        let mut layer = small();
+        assert!(
+            receive(&mut layer, fragment(0, 0, 4, &[0; 16]))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            receive(&mut layer, fragment(1, 0, 4, &[0; 16]))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            receive(&mut layer, fragment(2, 0, 4, &[0; 16]))
+                .unwrap()
+                .is_none()
+        );
+        // A fourth fragment would exceed the 48 bytes, so its message is discarded.
+        assert!(receive(&mut layer, fragment(0, 1, 4, &[0; 16])).is_err());
+        assert_eq!((layer.in_flight, layer.buffered), (2, 32));
+        assert!(!layer.partial.contains_key(&0));
+    }
+}
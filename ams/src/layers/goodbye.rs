@@ -0,0 +1,52 @@
+//! A controller layer for telling the remote peer that a disconnect is deliberate.
+use bytes::BytesMut;
+
+use super::{DATA, Incoming, Init, Signal};
+use crate::{DisconnectReason, transport::Transport};
+
+/// Tags a goodbye.
+const GOODBYE: u8 = 1;
+
+/// A [control layer](super#control-layers) that says goodbye to the remote peer before the local peer disconnects,
+/// so that each side can tell a deliberate disconnect from a lost connection.
+///
+/// The goodbye is sent when the connection is disconnected locally, with [crate::Ams::disconnect] or by shutting the
+/// instance down, after the messages already queued. A remote peer saying goodbye is disconnected with
+/// [DisconnectReason::Graceful].
+pub struct Goodbye;
+
+impl super::Layer for Goodbye {
+    const NAME: &'static str = "goodbye";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        match command {
+            Cmd::Goodbye => Some(super::tagged(GOODBYE)),
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            GOODBYE => Ok(Incoming::Handled(Some(Signal::Disconnect(
+                DisconnectReason::Graceful,
+            )))),
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// The commands handled by the [Goodbye] layer.
+pub enum Cmd {
+    /// Says goodbye to the remote peer. Issued by the connection itself once it is disconnected locally.
+    Goodbye,
+}
@@ -0,0 +1,92 @@
+//! A controller layer for introducing each peer by its nickname when a connection is established.
+use bytes::{Bytes, BytesMut};
+use futures_util::sink::SinkExt;
+use tokio_stream::StreamExt;
+
+use super::{Incoming, Init};
+use crate::transport::Transport;
+
+/// The longest nickname, in bytes, a peer may introduce itself with.
+pub const MAX_NICKNAME_LEN: usize = 64;
+
+/// A Controller layer that introduces the local peer to the remote peer with its [crate::AmsConfig::nickname], which
+/// is reported in [crate::Event::ConnectionEstablished].
+///
+/// While initializing, each peer sends its nickname, empty if it has none, and reads the other's. A nickname that is
+/// empty, longer than [MAX_NICKNAME_LEN] or not valid UTF-8 is ignored. Once introduced, frames pass through this layer
+/// untouched.
+pub struct Identity {
+    /// The nickname the remote peer introduced itself with.
+    remote: Option<String>,
+}
+
+impl super::Layer for Identity {
+    const NAME: &'static str = "identity";
+
+    // This layer does not accept any commands.
+    type Command = ();
+
+    async fn initialize(stream: &mut Transport, init: &Init) -> Result<Self, String> {
+        stream
+            .send(Bytes::copy_from_slice(
+                init.nickname().unwrap_or_default().as_bytes(),
+            ))
+            .await
+            .map_err(|e| format!("failed to send nickname: {e}"))?;
+        match stream.next().await {
+            Some(Ok(frame)) => Ok(Self {
+                remote: nickname(&frame),
+            }),
+            Some(Err(e)) => Err(format!("failed to read nickname: {e}")),
+            None => Err("the remote peer closed the connection".to_string()),
+        }
+    }
+
+    fn nickname(&self) -> Option<&str> {
+        self.remote.as_deref()
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<BytesMut> {
+        None
+    }
+
+    fn handle_outgoing_frame(&mut self, _frame: &mut BytesMut) {}
+
+    fn handle_incoming_frame(&mut self, _frame: &mut BytesMut) -> Result<Incoming, String> {
+        Ok(Incoming::Forward(None))
+    }
+}
+
+/// Returns the nickname a peer introduced itself with, or `None` if it is empty, too long or not valid UTF-8.
+fn nickname(bytes: &[u8]) -> Option<String> {
+    let nickname = std::str::from_utf8(bytes).ok()?.trim();
+    (!nickname.is_empty() && nickname.len() <= MAX_NICKNAME_LEN).then(|| nickname.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AmsConfig, layers::Layer, transport::Side};
+
+    #[tokio::test]
+    async fn peers_are_introduced_by_their_nicknames() {
+        let (mut local, mut remote) = crate::transport::duplex();
+        let config = AmsConfig::builder().nickname("alice").build();
+        let outbound = Init::new(Side::Outbound, &config);
+        let inbound = Init::new(Side::Inbound, &AmsConfig::default());
+        let (local, remote) = tokio::join!(
+            Identity::initialize(&mut local, &outbound),
+            Identity::initialize(&mut remote, &inbound),
+        );
+        assert_eq!(remote.unwrap().nickname(), Some("alice"));
+        assert_eq!(local.unwrap().nickname(), None);
+    }
+
+    #[test]
+    fn invalid_nicknames_are_ignored() {
+        assert_eq!(nickname(b" bob "), Some("bob".to_string()));
+        assert_eq!(nickname(b""), None);
+        assert_eq!(nickname(&[b'a'; MAX_NICKNAME_LEN + 1]), None);
+        assert_eq!(nickname(&[0xff]), None);
+    }
+}
@@ -0,0 +1,56 @@
+//! A controller layer for detecting corrupted frames.
+use bytes::{BufMut, BytesMut};
+
+use super::{Incoming, Init, Signal};
+use crate::{DisconnectReason, transport::Transport};
+
+/// The length of the checksum appended to every frame.
+const CHECKSUM_LEN: usize = 4;
+
+/// A Controller layer that appends a CRC32 checksum to every outgoing frame, and verifies and strips it from every
+/// incoming frame.
+///
+/// A frame whose checksum does not match is dropped, or, if `DISCONNECT` is set, the remote peer is disconnected with
+/// [DisconnectReason::Error].
+///
+/// The checksum covers the frame as produced by the layers above this one. To catch a layer that transforms frames
+/// (e.g. compression or encryption) corrupting them, place this layer above it, farther from the wire, so the checksum
+/// is verified on the frame that layer restored, e.g. `(Ping, Integrity, Transmit)`.
+pub struct Integrity<const DISCONNECT: bool = false>;
+
+impl<const DISCONNECT: bool> super::Layer for Integrity<DISCONNECT> {
+    const NAME: &'static str = "integrity";
+
+    // This layer does not accept any commands.
+    type Command = ();
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<BytesMut> {
+        None
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        let checksum = crc32fast::hash(frame);
+        frame.put_u32(checksum);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        if frame.len() < CHECKSUM_LEN {
+            return Err("frame too short to carry a checksum".to_string());
+        }
+        let checksum = frame.split_off(frame.len() - CHECKSUM_LEN);
+        let expected = u32::from_be_bytes(checksum[..].try_into().expect("checksum is 4 bytes"));
+        if crc32fast::hash(frame) == expected {
+            return Ok(Incoming::Forward(None));
+        }
+        if DISCONNECT {
+            return Ok(Incoming::Handled(Some(Signal::Disconnect(
+                DisconnectReason::Error,
+            ))));
+        }
+        Err("checksum mismatch".to_string())
+    }
+}
@@ -0,0 +1,94 @@
+//! A controller layer for measuring the round-trip time to the remote peer.
+use std::{collections::HashMap, time::Duration};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{sync::oneshot, time::Instant};
+
+use super::{DATA, Incoming, Init};
+use crate::transport::Transport;
+
+/// Tags a ping frame, carrying a nonce.
+const PING: u8 = 1;
+/// Tags a pong frame, echoing the nonce of the ping it answers.
+const PONG: u8 = 2;
+
+/// A [control layer](super#control-layers) that answers pings from the remote peer and measures the round-trip time
+/// of its own pings.
+///
+/// Each ping carries a unique nonce that is echoed back in the pong, so concurrent pings are correlated to the correct
+/// response.
+pub struct Ping {
+    /// The nonce of the next ping.
+    next_nonce: u64,
+    /// The outstanding pings, keyed by nonce.
+    pending: HashMap<u64, (Instant, oneshot::Sender<Option<Duration>>)>,
+}
+
+impl super::Layer for Ping {
+    const NAME: &'static str = "ping";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self {
+            next_nonce: 0,
+            pending: HashMap::new(),
+        })
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        match command {
+            Cmd::Ping(resp) => {
+                // Forget the pings whose requester has given up waiting.
+                self.pending.retain(|_, (_, resp)| !resp.is_closed());
+
+                let nonce = self.next_nonce;
+                self.next_nonce = self.next_nonce.wrapping_add(1);
+                self.pending.insert(nonce, (Instant::now(), resp));
+                Some(control_frame(PING, nonce))
+            }
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            PING => Ok(Incoming::Reply(control_frame(PONG, read_nonce(frame)?))),
+            PONG => {
+                let nonce = read_nonce(frame)?;
+                let (sent, resp) = self
+                    .pending
+                    .remove(&nonce)
+                    .ok_or_else(|| format!("unexpected pong with nonce {nonce}"))?;
+                let _ = resp.send(Some(sent.elapsed()));
+                Ok(Incoming::Handled(None))
+            }
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// Creates a ping or pong frame carrying the nonce.
+fn control_frame(tag: u8, nonce: u64) -> BytesMut {
+    let mut frame = super::tagged(tag);
+    frame.put_u64(nonce);
+    frame
+}
+
+/// Reads the nonce of a ping or pong frame, after its tag.
+fn read_nonce(frame: &mut BytesMut) -> Result<u64, String> {
+    if frame.len() < 8 {
+        return Err("truncated ping frame".to_string());
+    }
+    Ok(frame.get_u64())
+}
+
+/// The commands handled by the [Ping] layer.
+pub enum Cmd {
+    /// Pings the remote peer, responding with the round-trip time once the pong is received.
+    Ping(oneshot::Sender<Option<Duration>>),
+}
@@ -0,0 +1,61 @@
+//! A controller layer for exchanging the nickname and status of each peer.
+use bytes::BytesMut;
+
+use super::{DATA, Incoming, Init, Signal};
+use crate::{api::Presence as Announcement, transport::Transport};
+
+/// Tags a presence announcement.
+const PRESENCE: u8 = 1;
+
+/// A [control layer](super#control-layers) that announces the local presence to the remote peer, and reports the
+/// presence it announces.
+///
+/// Announcements received from the remote peer are signaled to the manager with [Signal::Presence] and reported with
+/// [crate::Event::PresenceUpdate].
+pub struct Presence;
+
+impl super::Layer for Presence {
+    const NAME: &'static str = "presence";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        match command {
+            Cmd::Announce(presence) => {
+                match postcard::to_extend(&presence, super::tagged(PRESENCE)) {
+                    Ok(bytes) => Some(bytes),
+                    Err(err) => {
+                        tracing::debug!(%err, "failed to encode presence");
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            PRESENCE => {
+                let presence = postcard::from_bytes::<Announcement>(frame)
+                    .map_err(|e| format!("failed to decode presence: {e}"))?;
+                Ok(Incoming::Handled(Some(Signal::Presence(presence))))
+            }
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// The commands handled by the [Presence] layer.
+pub enum Cmd {
+    /// Serializes the presence into a frame announcing it to the remote peer.
+    Announce(Announcement),
+}
@@ -0,0 +1,60 @@
+//! A controller layer for limiting the rate of incoming frames.
+use bytes::BytesMut;
+use tokio::time::Instant;
+
+use super::{Incoming, Init, Signal};
+use crate::{DisconnectReason, transport::Transport};
+
+/// A Controller layer that limits the rate of frames received from the remote peer with a token bucket.
+///
+/// The bucket holds up to `BURST` tokens and refills at `PER_SEC` tokens per second. Each incoming frame takes a token.
+/// Once the bucket is empty, frames are dropped, or, if `DISCONNECT` is set, the remote peer is disconnected with
+/// [DisconnectReason::RateLimited]. The layer should be placed closest to the wire so every frame is counted, e.g.
+/// `(RateLimit<100, 200>, Ping, Transmit)`.
+pub struct RateLimit<const PER_SEC: u32, const BURST: u32, const DISCONNECT: bool = false> {
+    /// The tokens currently available.
+    tokens: f64,
+    /// When the bucket was last refilled.
+    refilled: Instant,
+}
+
+impl<const PER_SEC: u32, const BURST: u32, const DISCONNECT: bool> super::Layer
+    for RateLimit<PER_SEC, BURST, DISCONNECT>
+{
+    const NAME: &'static str = "ratelimit";
+
+    // This layer does not accept any commands.
+    type Command = ();
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self {
+            tokens: f64::from(BURST),
+            refilled: Instant::now(),
+        })
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<BytesMut> {
+        None
+    }
+
+    fn handle_outgoing_frame(&mut self, _frame: &mut BytesMut) {}
+
+    fn handle_incoming_frame(&mut self, _frame: &mut BytesMut) -> Result<Incoming, String> {
+        // Refill lazily, based on the time elapsed since the last frame.
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(PER_SEC)).min(f64::from(BURST));
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(Incoming::Forward(None));
+        }
+        if DISCONNECT {
+            return Ok(Incoming::Handled(Some(Signal::Disconnect(
+                DisconnectReason::RateLimited,
+            ))));
+        }
+        Err(format!("exceeded {PER_SEC} frames per second"))
+    }
+}
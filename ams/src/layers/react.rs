@@ -0,0 +1,68 @@
+//! A controller layer for reacting to received messages.
+use bytes::BytesMut;
+
+use super::{DATA, Incoming, Init, Signal};
+use crate::{api::Reaction, transport::Transport};
+
+/// Tags a reaction.
+const REACTION: u8 = 1;
+/// The longest reaction accepted, in bytes. Long enough for any emoji, including sequences joined with ZWJs.
+const MAX_EMOJI_LEN: usize = 64;
+
+/// A [control layer](super#control-layers) that sends reactions to the messages received from the remote peer, and
+/// reports the reactions it sends.
+///
+/// Reactions received from the remote peer are signaled to the manager with [Signal::Reaction] and reported with
+/// [crate::Event::Reaction]. The message a reaction refers to is not checked, as the local peer may not have it
+/// anymore; applications should ignore reactions to messages they do not know.
+pub struct React;
+
+impl super::Layer for React {
+    const NAME: &'static str = "react";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        match command {
+            Cmd::React(reaction) => {
+                if reaction.emoji.is_empty() || reaction.emoji.len() > MAX_EMOJI_LEN {
+                    tracing::debug!(bytes = reaction.emoji.len(), "invalid reaction");
+                    return None;
+                }
+                postcard::to_extend(&reaction, super::tagged(REACTION)).ok()
+            }
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            REACTION => {
+                let reaction = postcard::from_bytes::<Reaction>(frame)
+                    .map_err(|e| format!("failed to decode reaction: {e}"))?;
+                if reaction.emoji.is_empty() || reaction.emoji.len() > MAX_EMOJI_LEN {
+                    return Err(format!(
+                        "invalid reaction of {} bytes",
+                        reaction.emoji.len()
+                    ));
+                }
+                Ok(Incoming::Handled(Some(Signal::Reaction(reaction))))
+            }
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// The commands handled by the [React] layer.
+pub enum Cmd {
+    /// Serializes the reaction into a frame to be sent to the remote peer.
+    React(Reaction),
+}
@@ -0,0 +1,78 @@
+//! A controller layer for exchanging room membership and messages sent to a room.
+use bytes::BytesMut;
+
+use super::{DATA, Incoming, Init, Signal, tagged};
+use crate::{api::Room as RoomMessage, transport::Transport};
+
+/// Tags the name of a room the remote peer joined.
+const JOIN: u8 = 1;
+/// Tags the name of a room the remote peer left.
+const LEAVE: u8 = 2;
+/// Tags a message sent to a room.
+const MESSAGE: u8 = 3;
+
+/// A [control layer](super#control-layers) that tells the remote peer which rooms the local peer is a member of, and
+/// carries the messages sent to those rooms.
+///
+/// Membership changes and room messages are both signaled to the manager, which only sends a room's messages to its
+/// members and reports received ones with [crate::Event::RoomMessage].
+pub struct Room;
+
+impl super::Layer for Room {
+    const NAME: &'static str = "room";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        let (tag, bytes) = match command {
+            Cmd::Join(room) => (JOIN, postcard::to_extend(&room, tagged(JOIN))),
+            Cmd::Leave(room) => (LEAVE, postcard::to_extend(&room, tagged(LEAVE))),
+            Cmd::Send(message) => (MESSAGE, postcard::to_extend(&message, tagged(MESSAGE))),
+        };
+        match bytes {
+            Ok(bytes) => Some(bytes),
+            Err(err) => {
+                tracing::debug!(%err, tag, "failed to encode room frame");
+                None
+            }
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            tag @ (JOIN | LEAVE) => {
+                let room = postcard::from_bytes::<String>(frame)
+                    .map_err(|e| format!("failed to decode room name: {e}"))?;
+                Ok(Incoming::Handled(Some(Signal::RoomMembership {
+                    room,
+                    joined: tag == JOIN,
+                })))
+            }
+            MESSAGE => {
+                let message = postcard::from_bytes::<RoomMessage>(frame)
+                    .map_err(|e| format!("failed to decode room message: {e}"))?;
+                Ok(Incoming::Handled(Some(Signal::RoomMessage(message))))
+            }
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// The commands handled by the [Room] layer.
+pub enum Cmd {
+    /// Tells the remote peer that the local peer joined the room.
+    Join(String),
+    /// Tells the remote peer that the local peer left the room.
+    Leave(String),
+    /// Serializes the message into a frame to be sent to the remote peer as a member of its room.
+    Send(RoomMessage),
+}
@@ -0,0 +1,110 @@
+//! A controller layer for detecting lost and duplicated frames.
+use bytes::{Buf, BufMut, BytesMut};
+
+use super::{Incoming, Init, Signal};
+use crate::transport::Transport;
+
+/// The length of the sequence number prefixed to every frame.
+const SEQUENCE_LEN: usize = 8;
+
+/// A Controller layer that stamps every outgoing frame with a sequence number, and checks the sequence numbers of the
+/// frames received from the remote peer.
+///
+/// A frame with a sequence number already seen is dropped, and reported with [crate::Event::LayerDropped]. When frames
+/// are skipped, the manager is signaled with
+/// [Signal::FramesLost] and reports them with [crate::Event::FramesLost], and the frame is passed on. Since a
+/// connection's stream is ordered, this mainly matters once frames of one session can take several paths (e.g. across
+/// a reconnection), so the numbering only depends on the frames this layer has seen, not on the underlying stream.
+pub struct Sequence {
+    /// The sequence number of the next outgoing frame.
+    next_outgoing: u64,
+    /// The sequence number expected for the next incoming frame.
+    next_incoming: u64,
+}
+
+impl super::Layer for Sequence {
+    const NAME: &'static str = "sequence";
+
+    // This layer does not accept any commands.
+    type Command = ();
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self {
+            next_outgoing: 0,
+            next_incoming: 0,
+        })
+    }
+
+    fn handle_cmd(&mut self, _command: Self::Command) -> Option<BytesMut> {
+        None
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        let mut stamped = BytesMut::with_capacity(frame.len() + SEQUENCE_LEN);
+        stamped.put_u64(self.next_outgoing);
+        stamped.extend_from_slice(frame);
+        *frame = stamped;
+        self.next_outgoing += 1;
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        if frame.len() < SEQUENCE_LEN {
+            return Err("frame too short to carry a sequence number".to_string());
+        }
+        let sequence = frame.get_u64();
+        if sequence < self.next_incoming {
+            return Err(format!("duplicate of frame {sequence}"));
+        }
+        let lost = sequence - self.next_incoming;
+        self.next_incoming = sequence + 1;
+        Ok(Incoming::Forward(
+            (lost > 0).then_some(Signal::FramesLost { count: lost }),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AmsConfig,
+        api::Message,
+        controller::Controller,
+        layers::transmit::{Cmd, Transmit},
+        transport::Side,
+    };
+
+    #[tokio::test]
+    async fn duplicate_frame_is_dropped_by_the_sequence_layer() {
+        let (mut local, mut remote) = crate::transport::duplex();
+        let init = Init::new(Side::Outbound, &AmsConfig::default());
+        let mut sender = <(Sequence, Transmit)>::initialize(&mut local, &init)
+            .await
+            .unwrap();
+        let mut receiver = <(Sequence, Transmit)>::initialize(&mut remote, &init)
+            .await
+            .unwrap();
+
+        let message = Message {
+            id: 7,
+            payload: b"hello".to_vec(),
+            sender: "sender".to_string(),
+        };
+        let frame = sender
+            .process_cmd(Box::new(Cmd::SendMessage(message)))
+            .unwrap()
+            .remove(0);
+
+        let Ok(processed) = receiver.process_incoming_frame(&mut frame.clone()) else {
+            panic!("the first frame was dropped");
+        };
+        assert!(matches!(
+            processed.signals[..],
+            [Signal::Message(Message { id: 7, .. })]
+        ));
+        let Err(dropped) = receiver.process_incoming_frame(&mut frame.clone()) else {
+            panic!("the duplicate frame was passed on");
+        };
+        assert_eq!(dropped.layer, "sequence");
+    }
+}
@@ -0,0 +1,68 @@
+//! A controller layer for relaying messages through a server.
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+
+use super::{DATA, Incoming, Init, Signal};
+use crate::{api::Server, transport::Transport};
+
+/// Tags a [Server] message, to be relayed by the remote peer to the wrapped recipient.
+const RELAY: u8 = 1;
+
+/// A [control layer](super#control-layers) that wraps messages for a server to relay, and unwraps the messages a
+/// server should relay.
+///
+/// The frames of the layers above this one are meant for the remote peer itself. When a [Server] message is received,
+/// the layer signals the manager to deliver the wrapped message to its recipient instead of handling it locally. Both
+/// the server and its clients must use a stack containing this layer, such as [crate::controller::StackKind::Relay].
+pub struct ServerRelay;
+
+impl super::Layer for ServerRelay {
+    const NAME: &'static str = "server-relay";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        match command {
+            Cmd::Relay(server) => match postcard::to_extend(&server, super::tagged(RELAY)) {
+                Ok(bytes) => Some(bytes),
+                Err(err) => {
+                    tracing::debug!(%err, "failed to encode server message");
+                    None
+                }
+            },
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            RELAY => {
+                let Server { recipient, message } = postcard::from_bytes::<Server>(frame)
+                    .map_err(|e| format!("failed to decode server message: {e}"))?;
+                let recipient = recipient
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("invalid recipient {recipient}: {e}"))?;
+                Ok(Incoming::Handled(Some(Signal::Relay {
+                    recipient,
+                    message,
+                })))
+            }
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// The commands handled by the [ServerRelay] layer.
+pub enum Cmd {
+    /// Wraps the message into a frame for the remote peer to relay.
+    Relay(Server),
+}
@@ -1,44 +1,104 @@
 //! A controller layer for transmitting and receiving raw messages.
 use bytes::BytesMut;
-use tokio::net::TcpStream;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-use crate::{Command, api::Message};
+use super::{Incoming, Init, Signal};
+use crate::{api::Message, format::MessageFormat, transport::Transport};
 
 /// A simple Controller layer for transmitting and receiving raw messages.
-pub struct Transmit;
+///
+/// Messages are serialized with the configured [crate::AmsConfig::format], which both peers of a connection must agree
+/// on. Messages received from the remote peer are signaled to the manager with [Signal::Message] and reported with
+/// [crate::Event::MessageReceived].
+pub struct Transmit {
+    format: MessageFormat,
+}
 
 impl super::Layer for Transmit {
+    const NAME: &'static str = "transmit";
+
     type Command = Cmd;
 
-    async fn initialize(_stream: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Self {
-        Self
+    async fn initialize(_stream: &mut Transport, init: &Init) -> Result<Self, String> {
+        Ok(Self {
+            format: init.format(),
+        })
     }
 
     fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
         match command {
-            Cmd::SendMessage(message) => {
-                let bytes = BytesMut::new();
-                let bytes = postcard::to_extend(&message, bytes).unwrap();
-                Some(bytes)
-            }
+            Cmd::SendMessage(message) => match self.format.serialize(&message, BytesMut::new()) {
+                Ok(bytes) => Some(bytes),
+                Err(err) => {
+                    tracing::debug!(%err, format = self.format.name(), "failed to encode message");
+                    None
+                }
+            },
         }
     }
 
     fn handle_outgoing_frame(&mut self, _frame: &mut bytes::BytesMut) {}
 
-    fn handle_incoming_frame(&mut self, frame: &mut bytes::BytesMut) -> Option<Command> {
-        if let Ok(msg) = postcard::from_bytes::<Message>(frame) {
-            println!(
-                "Received message: {}",
-                String::from_utf8_lossy(&msg.payload)
-            );
-            // TODO
-        };
-        None
+    fn handle_incoming_frame(&mut self, frame: &mut bytes::BytesMut) -> Result<Incoming, String> {
+        let msg = self
+            .format
+            .deserialize::<Message>(frame)
+            .map_err(|e| format!("failed to decode message: {e}"))?;
+        tracing::debug!(id = msg.id, bytes = msg.payload.len(), "received message");
+        Ok(Incoming::Handled(Some(Signal::Message(msg))))
     }
 }
 
+/// The commands handled by the [Transmit] layer.
 pub enum Cmd {
+    /// Serializes the message into a frame to be sent to the remote peer.
     SendMessage(Message),
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::sink::SinkExt;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{
+        AmsConfig,
+        controller::{Controller, Unsecure},
+        transport::Side,
+    };
+
+    #[tokio::test]
+    async fn message_is_transmitted_over_an_in_memory_stream() {
+        let (mut local, mut remote) = crate::transport::duplex();
+        let config = AmsConfig::default();
+        let outbound = Init::new(Side::Outbound, &config);
+        let inbound = Init::new(Side::Inbound, &config);
+        let (local_stack, remote_stack) = tokio::join!(
+            Unsecure::initialize(&mut local, &outbound),
+            Unsecure::initialize(&mut remote, &inbound)
+        );
+        let (mut local_stack, mut remote_stack) = (local_stack.unwrap(), remote_stack.unwrap());
+
+        let message = Message {
+            id: 7,
+            payload: b"hello".to_vec(),
+            sender: "127.0.0.1:4000".to_string(),
+        };
+        for frame in local_stack
+            .process_cmd(Box::new(Cmd::SendMessage(message)))
+            .unwrap()
+        {
+            local.send(frame.freeze()).await.unwrap();
+        }
+        let mut frame = remote.next().await.unwrap().unwrap();
+        let Ok(processed) = remote_stack.process_incoming_frame(&mut frame) else {
+            panic!("the frame was dropped");
+        };
+
+        let [Signal::Message(received)] = processed.signals.as_slice() else {
+            panic!("expected a single message signal");
+        };
+        assert_eq!(received.id, 7);
+        assert_eq!(received.payload, b"hello");
+        assert!(processed.replies.is_empty());
+    }
+}
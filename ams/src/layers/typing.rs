@@ -0,0 +1,65 @@
+//! A controller layer for telling the remote peer that the local user is typing.
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::time::Instant;
+
+use super::{DATA, Incoming, Init, Signal};
+use crate::transport::Transport;
+
+/// Tags a typing notification.
+const TYPING: u8 = 1;
+
+/// A [control layer](super#control-layers) that notifies the remote peer while the local user is typing, and reports
+/// the notifications it sends.
+///
+/// Notifications are debounced: at most one is sent every `DEBOUNCE_MS` milliseconds, however often [Cmd::Typing] is
+/// issued. Notifications received from the remote peer are signaled to the manager with [Signal::Typing] and reported
+/// with [crate::Event::PeerTyping].
+pub struct Typing<const DEBOUNCE_MS: u64 = 500> {
+    /// When the last notification was sent.
+    last_sent: Option<Instant>,
+}
+
+impl<const DEBOUNCE_MS: u64> super::Layer for Typing<DEBOUNCE_MS> {
+    const NAME: &'static str = "typing";
+
+    type Command = Cmd;
+
+    async fn initialize(_stream: &mut Transport, _init: &Init) -> Result<Self, String> {
+        Ok(Self { last_sent: None })
+    }
+
+    fn handle_cmd(&mut self, command: Self::Command) -> Option<BytesMut> {
+        match command {
+            Cmd::Typing => {
+                let now = Instant::now();
+                if let Some(last_sent) = self.last_sent
+                    && now.duration_since(last_sent) < Duration::from_millis(DEBOUNCE_MS)
+                {
+                    return None;
+                }
+                self.last_sent = Some(now);
+                Some(super::tagged(TYPING))
+            }
+        }
+    }
+
+    fn handle_outgoing_frame(&mut self, frame: &mut BytesMut) {
+        super::tag_data(frame);
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &mut BytesMut) -> Result<Incoming, String> {
+        match super::untag(frame)? {
+            DATA => Ok(Incoming::Forward(None)),
+            TYPING => Ok(Incoming::Handled(Some(Signal::Typing))),
+            tag => Err(format!("unknown frame tag {tag}")),
+        }
+    }
+}
+
+/// The commands handled by the [Typing] layer.
+pub enum Cmd {
+    /// Notifies the remote peer that the local user is typing, unless a notification was sent recently.
+    Typing,
+}
@@ -1,69 +1,421 @@
 #![doc = include_str!("../../README.md")]
 
 pub mod api;
+mod config;
 mod connection;
 mod connection_manager;
-mod controller;
-mod layers;
+pub mod controller;
+mod events;
+pub mod format;
+pub mod layers;
+mod stats;
+mod transfer;
+pub mod transport;
 
-use std::{net::SocketAddr, time::SystemTime};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
 
-use tokio::sync::mpsc;
+use tokio::sync::oneshot;
 
-use tokio_stream::{StreamExt, wrappers::UnboundedReceiverStream};
+use tokio_stream::{Stream, StreamExt};
 
-use crate::connection_manager::ConnectionManager;
+use crate::{
+    connection_manager::{ConnectionManager, Listen},
+    controller::{Controller, StackKind},
+};
+
+pub use config::{
+    AcceptPolicy, AcceptRateLimit, AmsConfig, AmsConfigBuilder, InboundMode, Rate, TcpKeepalive,
+};
+pub use stats::{AmsStats, ConnectionStats};
 
 /// The AMS instance.
 pub struct Ams {
+    /// The controller stack used by [Self::connect].
+    stack: StackKind,
+    /// How long [Self::ping] waits for an answer.
+    ping_timeout: Duration,
     /// The connection manager.
     manager: ConnectionManager,
     /// The event stream.
-    event_stream: UnboundedReceiverStream<Event>,
+    event_stream: events::EventReceiver,
+    /// The id of the next message sent.
+    next_message_id: AtomicU64,
 }
 
 impl Ams {
     /// Starts up an AMS instance on a task, binding to the specified address.
-    pub async fn bind(addr: impl ToString) -> std::io::Result<Self> {
-        let (event_tx, event_rx) = mpsc::unbounded_channel();
-        let stream = UnboundedReceiverStream::new(event_rx);
+    pub async fn bind(addr: impl ToString) -> Result<Self, AmsError> {
+        Self::bind_with(addr, AmsConfig::default()).await
+    }
+
+    /// Starts up an AMS instance on a task, binding to the specified address with the provided configuration.
+    pub async fn bind_with(addr: impl ToString, config: AmsConfig) -> Result<Self, AmsError> {
+        Self::spawn(Listen::Tcp(vec![addr.to_string()]), config).await
+    }
+
+    /// Starts up an AMS instance on a task, listening on a Unix domain socket created at the specified path.
+    ///
+    /// See [Self::bind_unix_with].
+    #[cfg(unix)]
+    pub async fn bind_unix(path: impl AsRef<std::path::Path>) -> Result<Self, AmsError> {
+        Self::bind_unix_with(path, AmsConfig::default()).await
+    }
+
+    /// Starts up an AMS instance on a task, listening on a Unix domain socket created at the specified path with the
+    /// provided configuration.
+    ///
+    /// Fails with [AmsError::Io] if a file already exists at the path, e.g. the socket of an instance that was not shut
+    /// down. The socket is removed once the instance shuts down. Unix domain socket peers have no socket address, so
+    /// each peer is identified by a unique address of the discard-only `100::/64` prefix instead. Since access to the
+    /// socket is controlled by its file permissions, inbound connections are not subject to the
+    /// [AmsConfig::accept_rate_limit] nor to the [AcceptPolicy::allowlist], and are not wrapped in TLS.
+    #[cfg(unix)]
+    pub async fn bind_unix_with(
+        path: impl AsRef<std::path::Path>,
+        config: AmsConfig,
+    ) -> Result<Self, AmsError> {
+        Self::spawn(Listen::Unix(path.as_ref().to_path_buf()), config).await
+    }
+
+    /// Starts up an AMS instance on a task, binding to each of the specified addresses.
+    ///
+    /// Inbound connections from any of the addresses behave identically. If more than one address fails to bind, an
+    /// [AmsError::Multiple] lists the error of each.
+    pub async fn bind_many(addrs: impl IntoIterator<Item = SocketAddr>) -> Result<Self, AmsError> {
+        Self::bind_many_with(addrs, AmsConfig::default()).await
+    }
+
+    /// Starts up an AMS instance on a task, binding to each of the specified addresses with the provided
+    /// configuration.
+    ///
+    /// Inbound connections from any of the addresses behave identically. If more than one address fails to bind, an
+    /// [AmsError::Multiple] lists the error of each.
+    pub async fn bind_many_with(
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        config: AmsConfig,
+    ) -> Result<Self, AmsError> {
+        Self::spawn(
+            Listen::Tcp(addrs.into_iter().map(|addr| addr.to_string()).collect()),
+            config,
+        )
+        .await
+    }
+
+    /// Starts up the manager task, accepting connections on the specified listeners.
+    async fn spawn(listen: Listen, config: AmsConfig) -> Result<Self, AmsError> {
+        let (event_tx, stream) = events::channel(config.event_capacity);
+        let stack = config.stack;
+        let ping_timeout = config.ping_timeout;
 
         Ok(Self {
-            manager: ConnectionManager::spawn(addr, event_tx).await?,
+            stack,
+            ping_timeout,
+            manager: ConnectionManager::spawn(listen, event_tx, config).await?,
             event_stream: stream,
+            next_message_id: AtomicU64::new(0),
         })
     }
 
+    /// Starts up an AMS instance on a task, binding to the specified address with the provided configuration. Inbound
+    /// connections and connections made with [Self::connect] use the `C` controller stack.
+    ///
+    /// See [layers] for how to implement a custom layer. The stack must include the
+    /// [Transmit](layers::transmit::Transmit) layer for messages to be sent.
+    pub async fn bind_with_controller<C: Controller>(
+        addr: impl ToString,
+        config: AmsConfig,
+    ) -> Result<Self, AmsError> {
+        let config = AmsConfig {
+            stack: StackKind::custom::<C>(),
+            ..config
+        };
+        Self::bind_with(addr, config).await
+    }
+
     /// An asynchronous method to get the next event that occurs.
+    ///
+    /// Returns `None` once the instance stopped running and every event emitted before was returned, e.g. after its
+    /// manager task panicked. Commands sent to a stopped instance fail with [SendError::Closed].
     pub async fn next_event(&mut self) -> Option<Event> {
         self.event_stream.next().await
     }
 
-    /// Sends a message to the specified peer.
+    /// Returns the stream of events emitted by the AMS instance.
+    ///
+    /// This is useful for processing events with stream combinators, e.g. to only handle [Event::MessageReceived]
+    /// events. Events taken from the returned stream are no longer returned by [Self::next_event].
+    pub fn events(&mut self) -> impl Stream<Item = Event> + '_ {
+        &mut self.event_stream
+    }
+
+    /// An asynchronous method to get the next event that occurs within the specified duration.
+    ///
+    /// Returns `None` if no event arrives within `dur`. Since `None` is also returned once the event stream is closed,
+    /// callers that need to distinguish the two should fall back to [Self::next_event], which only returns `None` when
+    /// the stream is closed.
+    pub async fn next_event_timeout(&mut self, dur: Duration) -> Option<Event> {
+        tokio::time::timeout(dur, self.next_event())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Sends a message to the specified peer, returning the id of the message.
+    ///
+    /// A [Event::MessageSent] or [Event::MessageFailed] event carrying the returned id will be emitted once the message
+    /// has been written to the peer, or failed to be.
+    pub async fn send_message(&self, peer: SocketAddr, message: Vec<u8>) -> Result<u64, SendError> {
+        let message_id = self.next_message_id();
+        self.send_command(Command::SendMessage {
+            message_id,
+            addr: peer,
+            data: message,
+            confirm: None,
+        })
+        .await?;
+        Ok(message_id)
+    }
+
+    /// Sends a message to the specified peer, waiting until it has been written to the peer.
     ///
-    /// A [Event::MessageSent] or
-    pub async fn send_message(&self, peer: SocketAddr, message: Vec<u8>) {
+    /// Returns when the message was sent, or [SendError::Failed] if it could not be. The [Event::MessageSent] or
+    /// [Event::MessageFailed] event is still emitted. Dropping the returned future does not cancel the message.
+    pub async fn send_message_confirmed(
+        &self,
+        peer: SocketAddr,
+        message: Vec<u8>,
+    ) -> Result<SystemTime, SendError> {
+        let (confirm, rx) = oneshot::channel();
         self.send_command(Command::SendMessage {
-            message_id: 0,
+            message_id: self.next_message_id(),
             addr: peer,
             data: message,
+            confirm: Some(confirm),
         })
-        .await;
+        .await?;
+        // The manager drops the sender without an outcome only if it stops running in the meantime.
+        rx.await.unwrap_or(Err(SendError::Closed))
+    }
+
+    /// Sends a message to the specified recipient, relayed by the specified server.
+    ///
+    /// The recipient is addressed by the socket address of its connection to the server, as seen by the server. Both
+    /// connections must use a stack able to relay messages, such as [StackKind::Relay]. A [Event::MessageSent] or
+    /// [Event::MessageFailed] event carrying the returned id will be emitted once the message has been written to the
+    /// server, or failed to be.
+    pub async fn send_via(
+        &self,
+        server: SocketAddr,
+        recipient: SocketAddr,
+        message: Vec<u8>,
+    ) -> Result<u64, SendError> {
+        let message_id = self.next_message_id();
+        self.send_command(Command::SendVia {
+            message_id,
+            server,
+            recipient,
+            data: message,
+        })
+        .await?;
+        Ok(message_id)
     }
 
     /// Disconnects the specified peer.
     ///
-    /// Once fully disconnected, an [Event::ConnectionDisconnected] event will be emitted.
-    pub async fn disconnect(&self, peer: SocketAddr) {
-        self.send_command(Command::Disconnect { addr: peer }).await;
+    /// The messages already queued for the peer are sent first, followed by a goodbye if the controller stack
+    /// includes a [layers::goodbye::Goodbye] layer, for at most 5 seconds. Once fully disconnected, an
+    /// [Event::ConnectionDisconnected] event will be emitted.
+    pub async fn disconnect(&self, peer: SocketAddr) -> Result<(), SendError> {
+        self.send_command(Command::Disconnect {
+            addr: peer,
+            reason: DisconnectReason::Local,
+        })
+        .await
     }
 
     /// Attempts to connect to the specified peer.
     ///
     /// A [Event::ConnectionEstablished] or [Event::ConnectionRejected] event will be emitted depending on the result
-    /// of the connection attempt.
-    pub async fn connect(&self, addr: SocketAddr) {
-        self.send_command(Command::Connect { addr }).await;
+    /// of the connection attempt. Connecting to a peer already connected, or still connecting, is rejected.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<(), SendError> {
+        self.connect_with(addr, self.stack).await
+    }
+
+    /// Attempts to connect to the specified peer using the selected controller stack.
+    ///
+    /// A [Event::ConnectionEstablished] or [Event::ConnectionRejected] event will be emitted depending on the result
+    /// of the connection attempt. Connecting to a peer already connected, or still connecting, is rejected, as is
+    /// connecting to a peer whose [AmsConfig::stack] is not made of the same layers.
+    pub async fn connect_with(&self, addr: SocketAddr, stack: StackKind) -> Result<(), SendError> {
+        self.send_command(Command::Connect { addr, stack }).await
+    }
+
+    /// Attempts to connect to the peer listening on the Unix domain socket at the specified path, see
+    /// [Self::bind_unix_with].
+    ///
+    /// Returns the address identifying the peer in events and commands, unique to this connection. A
+    /// [Event::ConnectionEstablished] or [Event::ConnectionRejected] event carrying it will be emitted depending on the
+    /// result of the connection attempt.
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<SocketAddr, SendError> {
+        let (resp, rx) = oneshot::channel();
+        self.send_command(Command::ConnectUnix {
+            path: path.as_ref().to_path_buf(),
+            stack: self.stack,
+            resp,
+        })
+        .await?;
+        rx.await.map_err(|_| SendError::Closed)
+    }
+
+    /// Sets the nickname and status announced to peers.
+    ///
+    /// The presence is announced to every connected peer, and to every peer connected from now on. Only connections
+    /// whose controller stack includes a [layers::presence::Presence] layer announce it.
+    pub async fn set_status(
+        &self,
+        nickname: impl Into<String>,
+        status: api::Status,
+    ) -> Result<(), SendError> {
+        self.send_command(Command::SetStatus {
+            presence: api::Presence {
+                nickname: nickname.into(),
+                status,
+            },
+        })
+        .await
+    }
+
+    /// Joins the room, so messages sent to it by peers are reported with [Event::RoomMessage].
+    ///
+    /// Membership is announced to every connected peer, and to every peer connected from now on. Only connections
+    /// whose controller stack includes a [layers::room::Room] layer announce it.
+    pub async fn join_room(&self, room: impl Into<String>) -> Result<(), SendError> {
+        self.send_command(Command::JoinRoom { room: room.into() })
+            .await
+    }
+
+    /// Leaves the room, if joined.
+    pub async fn leave_room(&self, room: impl Into<String>) -> Result<(), SendError> {
+        self.send_command(Command::LeaveRoom { room: room.into() })
+            .await
+    }
+
+    /// Sends a message to every connected peer that joined the room.
+    ///
+    /// An [Event::MessageSent] or [Event::MessageFailed] event carrying the returned id is emitted for each of those
+    /// peers. The room does not need to be joined to send messages to it.
+    pub async fn send_to_room(
+        &self,
+        room: impl Into<String>,
+        message: Vec<u8>,
+    ) -> Result<u64, SendError> {
+        let message_id = self.next_message_id();
+        self.send_command(Command::SendToRoom {
+            message_id,
+            room: room.into(),
+            data: message,
+        })
+        .await?;
+        Ok(message_id)
+    }
+
+    /// Sends the file at the specified path to the specified peer, returning the id of the transfer.
+    ///
+    /// The file is streamed in chunks by a [layers::file::File] layer, which both peers' controller stacks must include,
+    /// paced so that messages sent meanwhile are not delayed behind it. Its content is only sent once the peer accepted
+    /// the file, see [AmsConfig::prompt_files] and [AmsConfig::max_file_size]. The progress of the transfer is reported
+    /// with [Event::TransferProgress], and its outcome with [Event::TransferComplete] once the peer verified the file,
+    /// or [Event::TransferFailed], including if the peer refused the file or did not accept it within the
+    /// [AmsConfig::transfer_timeout].
+    pub async fn send_file(
+        &self,
+        peer: SocketAddr,
+        path: impl Into<PathBuf>,
+    ) -> Result<u64, SendError> {
+        let transfer_id = self.next_message_id();
+        self.send_command(Command::SendFile {
+            addr: peer,
+            transfer_id,
+            path: path.into(),
+        })
+        .await?;
+        Ok(transfer_id)
+    }
+
+    /// Reacts with an emoji to a message received from the specified peer.
+    ///
+    /// The peer is sent the reaction if its controller stack includes a [layers::react::React] layer, and reports it
+    /// with [Event::Reaction].
+    pub async fn react(
+        &self,
+        peer: SocketAddr,
+        message_id: u64,
+        emoji: impl Into<String>,
+    ) -> Result<(), SendError> {
+        self.send_command(Command::React {
+            addr: peer,
+            reaction: api::Reaction {
+                message_id,
+                emoji: emoji.into(),
+            },
+        })
+        .await
+    }
+
+    /// Notifies the specified peer that the local user is typing.
+    ///
+    /// Meant to be called on every keystroke: notifications are debounced by the [layers::typing::Typing] layer, and
+    /// ignored if the peer's controller stack does not include one.
+    pub async fn send_typing(&self, peer: SocketAddr) -> Result<(), SendError> {
+        self.send_command(Command::Typing { addr: peer }).await
+    }
+
+    /// Measures the round-trip time to the specified peer.
+    ///
+    /// Returns `None` if the peer is not connected, its controller stack cannot answer pings, or it does not answer
+    /// within [AmsConfig::ping_timeout].
+    pub async fn ping(&self, peer: SocketAddr) -> Option<Duration> {
+        let (resp, rx) = oneshot::channel();
+        self.send_command(Command::Ping { addr: peer, resp })
+            .await
+            .ok()?;
+        tokio::time::timeout(self.ping_timeout, rx)
+            .await
+            .ok()?
+            .ok()
+            .flatten()
+    }
+
+    /// Returns a snapshot of the throughput of the instance and each of its connections.
+    ///
+    /// Returns empty stats if the instance is no longer running.
+    pub async fn stats(&self) -> AmsStats {
+        let (resp, rx) = oneshot::channel();
+        // If the instance is no longer running, the response channel is dropped along with the command.
+        let _ = self.send_command(Command::Stats { resp }).await;
+        rx.await.unwrap_or_default()
+    }
+
+    /// Returns whether the specified peer is currently connected.
+    ///
+    /// Returns `false` while the connection is still being established, i.e. until [Event::ConnectionEstablished] is
+    /// emitted for it, and if the instance is no longer running.
+    pub async fn is_connected(&self, peer: SocketAddr) -> bool {
+        let (resp, rx) = oneshot::channel();
+        // If the instance is no longer running, the response channel is dropped along with the command.
+        let _ = self
+            .send_command(Command::IsConnected { addr: peer, resp })
+            .await;
+        rx.await.unwrap_or(false)
     }
 
     /// Shuts down the AMS instance, closing all connections.
@@ -71,40 +423,213 @@ impl Ams {
         self.manager.shutdown().await;
     }
 
-    /// Sends a command to the manager task.
-    async fn send_command(&self, command: Command) {
-        self.manager.send_command(command).await;
+    /// Shuts down the AMS instance, closing all connections.
+    ///
+    /// Connections flush the messages already queued for them before closing. Any connection that has not closed
+    /// within the specified duration is aborted, in which case [Shutdown::Forced] is returned.
+    pub async fn shutdown_timeout(self, dur: Duration) -> Shutdown {
+        self.manager.shutdown_timeout(dur).await
+    }
+
+    /// Sends a command to the manager task, failing if the instance is no longer running.
+    async fn send_command(&self, command: Command) -> Result<(), SendError> {
+        self.manager.send_command(command).await
+    }
+
+    /// Allocates the id of a message about to be sent.
+    fn next_message_id(&self) -> u64 {
+        self.next_message_id.fetch_add(1, Ordering::Relaxed)
     }
 }
 
 enum Command {
     Connect {
         addr: SocketAddr,
+        stack: StackKind,
+    },
+    #[cfg(unix)]
+    ConnectUnix {
+        path: PathBuf,
+        stack: StackKind,
+        resp: oneshot::Sender<SocketAddr>,
     },
     Disconnect {
         addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+    Established {
+        addr: SocketAddr,
+        nickname: Option<String>,
+    },
+    Rejected {
+        addr: SocketAddr,
     },
     SendMessage {
         message_id: u64,
         addr: SocketAddr,
         data: Vec<u8>,
+        confirm: Option<oneshot::Sender<Result<SystemTime, SendError>>>,
+    },
+    MessageSent {
+        addr: SocketAddr,
+        message_id: u64,
+    },
+    MessageFailed {
+        addr: SocketAddr,
+        message_id: u64,
+        reason: FailureReason,
+    },
+    LayerDropped {
+        addr: SocketAddr,
+        layer: &'static str,
+        reason: String,
+    },
+    Ping {
+        addr: SocketAddr,
+        resp: oneshot::Sender<Option<Duration>>,
+    },
+    Stats {
+        resp: oneshot::Sender<AmsStats>,
+    },
+    #[cfg(test)]
+    PendingConfirmations {
+        resp: oneshot::Sender<usize>,
+    },
+    IsConnected {
+        addr: SocketAddr,
+        resp: oneshot::Sender<bool>,
+    },
+    SendVia {
+        message_id: u64,
+        server: SocketAddr,
+        recipient: SocketAddr,
+        data: Vec<u8>,
+    },
+    Relay {
+        addr: SocketAddr,
+        recipient: SocketAddr,
+        message: api::Message,
+    },
+    FramesLost {
+        addr: SocketAddr,
+        count: u64,
+    },
+    SetStatus {
+        presence: api::Presence,
+    },
+    PresenceUpdate {
+        addr: SocketAddr,
+        presence: api::Presence,
+    },
+    Typing {
+        addr: SocketAddr,
+    },
+    PeerTyping {
+        addr: SocketAddr,
+    },
+    React {
+        addr: SocketAddr,
+        reaction: api::Reaction,
+    },
+    PeerReacted {
+        addr: SocketAddr,
+        reaction: api::Reaction,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    SendToRoom {
+        message_id: u64,
+        room: String,
+        data: Vec<u8>,
+    },
+    RoomMembership {
+        addr: SocketAddr,
+        room: String,
+        joined: bool,
+    },
+    RoomMessage {
+        addr: SocketAddr,
+        message: api::Room,
+    },
+    MessageReceived {
+        addr: SocketAddr,
+        message: api::Message,
+    },
+    SendFile {
+        addr: SocketAddr,
+        transfer_id: u64,
+        path: PathBuf,
+    },
+    TransferAborted {
+        addr: SocketAddr,
+        transfer_id: u64,
+    },
+    Transfer {
+        addr: SocketAddr,
+        transfer: api::Transfer,
+    },
+    FileReceived {
+        addr: SocketAddr,
+        transfer_id: u64,
+        file: Option<(PathBuf, String)>,
+    },
+    AcceptTransfer {
+        addr: SocketAddr,
+        transfer_id: u64,
     },
 }
 
 /// Events emitted by the AMS instance via [Ams::next_event].
+///
+/// New events may be added in future releases, so matches on an event must include a catch-all arm:
+///
+/// ```no_run
+/// # async fn run(mut ams: ams::Ams) {
+/// use ams::Event;
+///
+/// while let Some(event) = ams.next_event().await {
+///     match event {
+///         Event::ConnectionRequested { response, .. } => {
+///             let _ = response.send(true);
+///         }
+///         Event::ConnectionDisconnected { peer, reason } => {
+///             println!("{peer} disconnected: {reason:?}");
+///         }
+///         _ => {}
+///     }
+/// }
+/// # }
+/// ```
+#[non_exhaustive]
 pub enum Event {
-    /// A new connection is being requested
+    /// A new connection is being requested, only emitted in the [InboundMode::Prompt] mode.
     ConnectionRequested {
         /// The peer address requesting the connection
         peer: SocketAddr,
         /// A channel to respond to the connection request
         response: tokio::sync::oneshot::Sender<bool>,
     },
-    /// A connection requested by a peer has been successfully established.
+    /// A connection has been successfully established.
+    ///
+    /// Emitted once the stream is framed and every layer of the controller stack is initialized, which may involve
+    /// exchanging frames with the peer.
     ConnectionEstablished {
         /// The socket addr of the established connection
         peer: SocketAddr,
+        /// The [AmsConfig::nickname] the peer introduced itself with, as exchanged by a [layers::identity::Identity]
+        /// layer
+        ///
+        /// `None` if the peer has no valid nickname, its controller stack does not include an Identity layer, or it
+        /// introduced itself with the nickname of another established connection, in which case it should be shown by
+        /// its address.
+        nickname: Option<String>,
     },
+    /// A connection was refused, failed to open, or could not be established, such as when a layer failed to
+    /// authenticate the peer or the peer did not complete the handshake within [AmsConfig::handshake_timeout].
     ConnectionRejected {
         /// The socket addr of the rejected connection
         peer: SocketAddr,
@@ -113,6 +638,8 @@ pub enum Event {
     ConnectionDisconnected {
         /// The socket addr of the disconnected connection
         peer: SocketAddr,
+        /// Why the connection was disconnected
+        reason: DisconnectReason,
     },
     /// A message received from a peer
     MessageReceived {
@@ -140,5 +667,257 @@ pub enum Event {
         peer: SocketAddr,
         /// The unique id of the message
         message_id: u64,
+        /// Why the message could not be sent
+        reason: FailureReason,
+    },
+    /// A layer discarded a frame received from a peer
+    LayerDropped {
+        /// The peer address the frame was received from
+        peer: SocketAddr,
+        /// The name of the layer that discarded the frame
+        layer: &'static str,
+        /// Why the layer discarded the frame
+        reason: String,
+    },
+    /// Frames sent by a peer were lost, as detected by a [layers::sequence::Sequence] layer
+    FramesLost {
+        /// The peer address the frames were sent by
+        peer: SocketAddr,
+        /// The number of frames lost
+        count: u64,
+    },
+    /// A peer announced its nickname and status, as exchanged by a [layers::presence::Presence] layer
+    PresenceUpdate {
+        /// The peer address that announced its presence
+        peer: SocketAddr,
+        /// The name the peer goes by
+        nickname: String,
+        /// Whether the peer is available
+        status: api::Status,
+    },
+    /// A peer notified that its user is typing, as exchanged by a [layers::typing::Typing] layer
+    ///
+    /// Notifications are debounced by the sender, so the peer may be considered to have stopped typing if no further
+    /// event is received within a second or so.
+    PeerTyping {
+        /// The peer address that is typing
+        peer: SocketAddr,
+    },
+    /// The bytes exchanged with a peer during the last [AmsConfig::throughput_interval]
+    ///
+    /// Dividing the byte counts by the window gives the current transfer rate, e.g. in KB/s.
+    Throughput {
+        /// The peer address the bytes were exchanged with
+        peer: SocketAddr,
+        /// The number of bytes received from the peer
+        bytes_in: u64,
+        /// The number of bytes sent to the peer
+        bytes_out: u64,
+        /// The duration the bytes were counted over
+        window: Duration,
+    },
+    /// A peer reacted to a message sent to it, as exchanged by a [layers::react::React] layer
+    ///
+    /// The message may be unknown, e.g. if it was sent before the instance was restarted.
+    Reaction {
+        /// The peer address that reacted
+        peer: SocketAddr,
+        /// The id of the message reacted to, as returned by [Ams::send_message]
+        message_id: u64,
+        /// The emoji reacted with
+        emoji: String,
+    },
+    /// A chunk of a file sent with [Ams::send_file] was queued
+    ///
+    /// Emitted at most once per percent of the file sent.
+    TransferProgress {
+        /// The peer address the file is sent to
+        peer: SocketAddr,
+        /// The id of the transfer, as returned by [Ams::send_file]
+        transfer_id: u64,
+        /// The number of bytes sent so far
+        bytes: u64,
+        /// The size of the file, in bytes
+        total: u64,
+    },
+    /// A file sent with [Ams::send_file] was received intact by the peer
+    TransferComplete {
+        /// The peer address the file was sent to
+        peer: SocketAddr,
+        /// The id of the transfer, as returned by [Ams::send_file]
+        transfer_id: u64,
+    },
+    /// A file sent with [Ams::send_file] could not be read, sent or verified by the peer
+    TransferFailed {
+        /// The peer address the file was sent to
+        peer: SocketAddr,
+        /// The id of the transfer, as returned by [Ams::send_file]
+        transfer_id: u64,
+    },
+    /// A peer offered a file, only emitted if [AmsConfig::prompt_files] is enabled
+    ///
+    /// The file is received if the application answers `true` within the [AmsConfig::transfer_timeout], and reported
+    /// with [Event::FileReceived].
+    FileOffered {
+        /// The peer address offering the file
+        peer: SocketAddr,
+        /// The id of the transfer, given by the peer
+        transfer_id: u64,
+        /// The name of the file given by the peer, without its directory. It is not sanitized further.
+        name: String,
+        /// The size of the file, in bytes
+        size: u64,
+        /// A channel to respond to the offer
+        response: tokio::sync::oneshot::Sender<bool>,
+    },
+    /// A file was received from a peer and its checksum verified, as exchanged by a [layers::file::File] layer
+    FileReceived {
+        /// The peer address that sent the file
+        peer: SocketAddr,
+        /// The temporary file the content was written to, which the application is responsible for moving or
+        /// removing
+        path: PathBuf,
+        /// The name of the file given by the peer, without its directory. It is not sanitized further.
+        name: String,
+    },
+    /// A message sent to a joined room by a peer, as exchanged by a [layers::room::Room] layer
+    RoomMessage {
+        /// The name of the room
+        room: String,
+        /// The peer address that sent the message
+        peer: SocketAddr,
+        /// The unique id of the message
+        message_id: u64,
+        /// The message payload
+        payload: Vec<u8>,
     },
 }
+
+/// The outcome of [Ams::shutdown_timeout].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Every connection closed within the timeout.
+    Clean,
+    /// At least one connection did not close within the timeout and was aborted.
+    Forced,
+}
+
+/// The error returned when a request cannot be handed to the AMS instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The instance is no longer running, e.g. because it was shut down.
+    Closed,
+    /// The message could not be sent to the peer, e.g. because it is not connected.
+    Failed,
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Closed => write!(f, "the AMS instance is no longer running"),
+            Self::Failed => write!(f, "the message could not be sent"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// The error returned when an AMS instance fails to start, by [Ams::bind] and its variants.
+#[derive(Debug)]
+pub enum AmsError {
+    /// The address could not be resolved to any socket address, e.g. because it is malformed.
+    InvalidAddress {
+        /// The address, as provided
+        addr: String,
+        /// Why the address could not be resolved
+        source: std::io::Error,
+    },
+    /// Another socket, e.g. another instance, is already bound to the address.
+    AlreadyRunning {
+        /// The address
+        addr: SocketAddr,
+    },
+    /// A listener could not be bound to the address, e.g. because binding it requires privileges.
+    BindFailed {
+        /// The address
+        addr: SocketAddr,
+        /// The error returned by the operating system
+        source: std::io::Error,
+    },
+    /// Several addresses failed to bind, each with its own error.
+    Multiple(Vec<AmsError>),
+    /// The configuration is invalid.
+    InvalidConfig(String),
+    /// Any other I/O error.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for AmsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress { addr, source } => write!(f, "invalid address {addr}: {source}"),
+            Self::AlreadyRunning { addr } => write!(f, "{addr} is already in use"),
+            Self::BindFailed { addr, source } => write!(f, "failed to bind to {addr}: {source}"),
+            Self::Multiple(errors) => {
+                write!(f, "failed to bind to several addresses: ")?;
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{error}")?;
+                }
+                Ok(())
+            }
+            Self::InvalidConfig(reason) => write!(f, "invalid configuration: {reason}"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for AmsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidAddress { source, .. } | Self::BindFailed { source, .. } => Some(source),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for AmsError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// The reason a connection was disconnected, reported by [Event::ConnectionDisconnected].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection was disconnected locally via [Ams::disconnect].
+    Local,
+    /// The remote peer closed the connection.
+    Remote,
+    /// The remote peer disconnected deliberately, saying goodbye with a [layers::goodbye::Goodbye] layer.
+    Graceful,
+    /// An I/O error occurred on the connection.
+    Error,
+    /// No frame was received and no command was processed within the configured [AmsConfig::idle_timeout].
+    Timeout,
+    /// The remote peer sent frames faster than allowed by a [layers::ratelimit::RateLimit] layer.
+    RateLimited,
+    /// The task running the connection panicked, e.g. in a layer of its controller stack.
+    Panicked,
+}
+
+/// The reason a message could not be sent, reported by [Event::MessageFailed].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The peer was not connected.
+    NotConnected,
+    /// The connection was closed before the message was written to the peer.
+    Disconnected,
+    /// No layer of the connection's controller stack handled the message, or it could not be encoded.
+    Unhandled,
+    /// The message was too large to be split into the fragments allowed by the [AmsConfig::fragment_limits].
+    TooManyFragments,
+}
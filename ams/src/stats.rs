@@ -0,0 +1,96 @@
+//! Throughput metrics for an AMS instance and its connections.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// A snapshot of the metrics of an AMS instance, returned by [crate::Ams::stats].
+#[derive(Debug, Clone, Default)]
+pub struct AmsStats {
+    /// How long the instance has been running.
+    pub uptime: Duration,
+    /// The number of currently established connections.
+    pub connections: usize,
+    /// The metrics of every established connection, summed. Its uptime is that of the longest established connection.
+    pub total: ConnectionStats,
+    /// The metrics of each established connection.
+    pub per_connection: HashMap<SocketAddr, ConnectionStats>,
+    /// The number of events dropped because the consumer of [crate::Ams::next_event] fell behind, see
+    /// [crate::AmsConfig::event_capacity].
+    pub events_dropped: u64,
+}
+
+/// A snapshot of the metrics of a single connection.
+///
+/// Messages are counted per frame written to or read from the wire, so control frames exchanged by layers (e.g. pings)
+/// are included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// How long the connection has been established.
+    pub uptime: Duration,
+    /// The number of bytes sent to the peer.
+    pub bytes_sent: u64,
+    /// The number of bytes received from the peer.
+    pub bytes_received: u64,
+    /// The number of messages sent to the peer.
+    pub messages_sent: u64,
+    /// The number of messages received from the peer.
+    pub messages_received: u64,
+}
+
+impl std::ops::AddAssign for ConnectionStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.uptime = self.uptime.max(rhs.uptime);
+        self.bytes_sent += rhs.bytes_sent;
+        self.bytes_received += rhs.bytes_received;
+        self.messages_sent += rhs.messages_sent;
+        self.messages_received += rhs.messages_received;
+    }
+}
+
+/// The live counters of a connection, shared between its task and the manager.
+pub(crate) struct Counters {
+    established: Instant,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self {
+            established: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a frame of the specified length written to the peer.
+    pub fn sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a frame of the specified length read from the peer.
+    pub fn received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            uptime: self.established.elapsed(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+        }
+    }
+}
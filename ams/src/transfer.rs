@@ -0,0 +1,239 @@
+//! The tasks sending and receiving the files transferred by a [crate::layers::file::File] layer.
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    time::Timeout,
+};
+
+use crate::{Command, api::Transfer, connection::BulkQueue, layers::file::Cmd};
+
+/// The number of bytes of a file sent in each chunk.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Sends the file at the specified path through the queue, as the transfer with the specified id.
+///
+/// The file is announced to the remote peer, and its content only sent once the remote peer accepted it, as signaled
+/// through `accepted` before it times out. The progress of the transfer is reported with
+/// [crate::Event::TransferProgress], at most once per percent sent. If the file cannot be read, is not accepted in time
+/// or the connection terminates, the manager is sent a [Command::TransferAborted]. Otherwise, the outcome is reported
+/// by the remote peer once it verified the file.
+pub(crate) async fn send(
+    queue: BulkQueue,
+    path: PathBuf,
+    id: u64,
+    addr: SocketAddr,
+    accepted: Timeout<oneshot::Receiver<()>>,
+    event_tx: crate::events::EventSender,
+    manager_tx: mpsc::Sender<Command>,
+) {
+    if let Err(err) = stream(&queue, &path, id, addr, accepted, &event_tx).await {
+        tracing::info!(peer = %addr, transfer_id = id, path = %path.display(), %err, "failed to send file");
+        let _ = manager_tx
+            .send(Command::TransferAborted {
+                addr,
+                transfer_id: id,
+            })
+            .await;
+    }
+}
+
+/// Streams the file through the queue, one part of the transfer at a time.
+async fn stream(
+    queue: &BulkQueue,
+    path: &PathBuf,
+    id: u64,
+    addr: SocketAddr,
+    accepted: Timeout<oneshot::Receiver<()>>,
+    event_tx: &crate::events::EventSender,
+) -> Result<(), String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| e.to_string())?;
+    let size = file.metadata().await.map_err(|e| e.to_string())?.len();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or("not a file")?;
+
+    let send = |transfer| async {
+        if queue.send(id, Box::new(Cmd::Send(transfer))).await {
+            Ok(())
+        } else {
+            Err("the connection terminated".to_string())
+        }
+    };
+    send(Transfer::Start { id, name, size }).await?;
+    // If the remote peer refused the file, the manager reported the outcome already and dropped the sender.
+    match accepted.await {
+        Ok(Ok(())) => {}
+        Ok(Err(_)) => return Err("the peer refused the file".to_string()),
+        Err(_) => return Err("the peer did not accept the file in time".to_string()),
+    }
+
+    let mut hasher = Sha256::new();
+    let mut sent = 0;
+    let mut reported = None;
+    loop {
+        let mut data = vec![0; CHUNK_LEN];
+        let len = file.read(&mut data).await.map_err(|e| e.to_string())?;
+        if len == 0 {
+            break;
+        }
+        data.truncate(len);
+        hasher.update(&data);
+        send(Transfer::Chunk { id, data }).await?;
+
+        sent += len as u64;
+        let percent = sent * 100 / size.max(1);
+        if reported != Some(percent) {
+            reported = Some(percent);
+            event_tx.send(crate::Event::TransferProgress {
+                peer: addr,
+                transfer_id: id,
+                bytes: sent,
+                total: size,
+            });
+        }
+    }
+    if sent != size {
+        return Err(format!("read {sent} bytes of a {size} bytes file"));
+    }
+    send(Transfer::End {
+        id,
+        checksum: hasher.finalize().into(),
+    })
+    .await
+}
+
+/// A file announced by a remote peer with [Transfer::Start].
+pub(crate) struct Offer {
+    /// The id of the transfer.
+    pub id: u64,
+    /// The name of the file, without its directory.
+    pub name: String,
+    /// The size of the file, in bytes.
+    pub size: u64,
+}
+
+/// Writes the file offered by a remote peer to a temporary file, from the parts of the transfer received through the
+/// channel.
+///
+/// If an `answer` is provided, the file is only received if the application accepts it before the answer times out.
+/// Once accepted, the manager is sent a [Command::AcceptTransfer], and each part must be received within the
+/// `timeout`. Once the transfer ends, or fails, the manager is sent a [Command::FileReceived] with the path of the
+/// file, if it was received intact. A file whose transfer failed is removed.
+pub(crate) async fn receive(
+    mut parts: mpsc::Receiver<Transfer>,
+    offer: Offer,
+    addr: SocketAddr,
+    answer: Option<Timeout<oneshot::Receiver<bool>>>,
+    timeout: Duration,
+    manager_tx: mpsc::Sender<Command>,
+) {
+    let Offer { id, name, size } = offer;
+    let accepted = match answer {
+        Some(answer) => matches!(answer.await, Ok(Ok(true))),
+        None => true,
+    };
+    let file = if accepted {
+        let _ = manager_tx
+            .send(Command::AcceptTransfer {
+                addr,
+                transfer_id: id,
+            })
+            .await;
+        let path = std::env::temp_dir().join(format!("ams-{id}-{:016x}", OsRng.next_u64()));
+        match write(&mut parts, &path, size, timeout).await {
+            Ok(()) => Some((path, name)),
+            Err(err) => {
+                tracing::info!(peer = %addr, transfer_id = id, %err, "failed to receive file");
+                let _ = tokio::fs::remove_file(&path).await;
+                None
+            }
+        }
+    } else {
+        tracing::info!(peer = %addr, transfer_id = id, "refused file");
+        None
+    };
+    let _ = manager_tx
+        .send(Command::FileReceived {
+            addr,
+            transfer_id: id,
+            file,
+        })
+        .await;
+}
+
+/// Writes the received chunks to the file at the specified path, verifying its size and checksum.
+async fn write(
+    parts: &mut mpsc::Receiver<Transfer>,
+    path: &PathBuf,
+    size: u64,
+    timeout: Duration,
+) -> Result<(), String> {
+    let mut file = tokio::fs::File::create_new(path)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    loop {
+        let part = tokio::time::timeout(timeout, parts.recv())
+            .await
+            .map_err(|_| "no part received in time".to_string())?;
+        match part {
+            Some(Transfer::Chunk { data, .. }) => {
+                received += data.len() as u64;
+                if received > size {
+                    return Err(format!("received more than the announced {size} bytes"));
+                }
+                hasher.update(&data);
+                file.write_all(&data).await.map_err(|e| e.to_string())?;
+            }
+            Some(Transfer::End { checksum, .. }) => {
+                if received != size {
+                    return Err(format!("received {received} of the announced {size} bytes"));
+                }
+                if hasher.finalize()[..] != checksum {
+                    return Err("checksum mismatch".to_string());
+                }
+                return file.flush().await.map_err(|e| e.to_string());
+            }
+            Some(part) => return Err(format!("unexpected part {part:?}")),
+            None => return Err("the transfer was interrupted".to_string()),
+        }
+    }
+}
+
+/// Returns the name of a received file without its directory, if it has one.
+pub(crate) fn file_name(name: &str) -> Option<String> {
+    let name = std::path::Path::new(name).file_name()?.to_str()?;
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn transfer_without_progress_fails() {
+        let (parts_tx, mut parts) = mpsc::channel(1);
+        let path = std::env::temp_dir().join(format!("ams-test-{:016x}", OsRng.next_u64()));
+        parts_tx
+            .send(Transfer::Chunk {
+                id: 0,
+                data: vec![0; 8],
+            })
+            .await
+            .unwrap();
+
+        // The sender stays connected, but sends nothing more.
+        let result = write(&mut parts, &path, 16, Duration::from_secs(30)).await;
+        assert_eq!(result, Err("no part received in time".to_string()));
+        drop(parts_tx);
+        let _ = std::fs::remove_file(path);
+    }
+}
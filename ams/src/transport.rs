@@ -0,0 +1,356 @@
+//! The byte streams that connections to remote AMS peers are carried over.
+//!
+//! Connections are carried over plain TCP by default. With the `tls` feature enabled and `TlsConfig` configured via
+//! `AmsConfig::tls`, the TCP stream is wrapped in a TLS stream before any frame is exchanged, so the layers of a
+//! controller stack always operate on the already decrypted stream. The peers then agree on the [CodecConfig] framing
+//! the stream, on the layers of their controller stacks and on their message format. If either step fails, the connection is rejected with
+//! [crate::Event::ConnectionRejected].
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::{AmsConfig, controller::StackKind, format::MessageFormat};
+
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+
+/// The version of the wire protocol, exchanged in the preamble so that peers unable to understand each other's frames
+/// reject the connection instead of misinterpreting them. Incremented whenever the frames of the built-in layers change.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The length of the preamble each peer sends before any frame.
+const PREAMBLE_LEN: usize = 11;
+
+/// A byte stream to a remote peer, such as a TCP or TLS stream.
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Io for T {}
+
+/// The framed stream to a remote peer that the layers of a controller stack are initialized with.
+///
+/// Any [Io] stream can be framed, not only TCP and TLS streams. For example, the controller stacks of two peers can be
+/// initialized over an in-memory stream, without binding a socket:
+///
+/// ```
+/// use ams::{
+///     AmsConfig,
+///     controller::{Controller, Unsecure},
+///     layers::Init,
+///     transport::{Side, Transport},
+/// };
+/// use tokio_util::codec::{Framed, LengthDelimitedCodec};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), String> {
+/// let (local, remote) = tokio::io::duplex(1024);
+/// let mut local: Transport = Framed::new(Box::new(local), LengthDelimitedCodec::new());
+/// let mut remote: Transport = Framed::new(Box::new(remote), LengthDelimitedCodec::new());
+/// let config = AmsConfig::default();
+/// let (outbound, inbound) = (Init::new(Side::Outbound, &config), Init::new(Side::Inbound, &config));
+/// // Layers such as Identity exchange frames with the remote peer while initializing.
+/// let (local_stack, remote_stack) = tokio::join!(
+///     Unsecure::initialize(&mut local, &outbound),
+///     Unsecure::initialize(&mut remote, &inbound),
+/// );
+/// let (_local_stack, _remote_stack) = (local_stack?, remote_stack?);
+/// # Ok(())
+/// # }
+/// ```
+pub type Transport = Framed<Box<dyn Io>, LengthDelimitedCodec>;
+
+/// The length prefix of every frame sent to and received from a remote peer.
+///
+/// Both peers of a connection must use the same codec. Before any frame is exchanged, each peer sends a short
+/// preamble carrying its [PROTOCOL_VERSION], describing its codec and identifying the layers of its controller stack
+/// and its [crate::format::MessageFormat], and the connection is rejected with [crate::Event::ConnectionRejected] if
+/// the two differ instead of mis-framing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecConfig {
+    /// The number of bytes of the length prefix, between 1 and 8.
+    pub length_field_length: usize,
+    /// Whether the length prefix is little endian instead of big endian.
+    pub little_endian: bool,
+    /// Whether the length prefix counts its own bytes in addition to the frame's.
+    pub length_includes_header: bool,
+    /// The largest frame sent to or accepted from the remote peer, in bytes. A larger frame disconnects the peer with
+    /// [crate::DisconnectReason::Error].
+    ///
+    /// Unlike the length prefix, this limit is not part of the preamble, so peers may configure it differently.
+    pub max_frame_length: usize,
+}
+
+impl Default for CodecConfig {
+    /// A 4 byte, big endian length prefix that only counts the frame's bytes, accepting frames of up to 8 MiB.
+    fn default() -> Self {
+        Self {
+            length_field_length: 4,
+            little_endian: false,
+            length_includes_header: false,
+            max_frame_length: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl CodecConfig {
+    /// Returns an error if the codec cannot be built.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if !(1..=8).contains(&self.length_field_length) {
+            return Err(format!(
+                "length field length must be between 1 and 8, got {}",
+                self.length_field_length
+            ));
+        }
+        Ok(())
+    }
+
+    /// Builds the codec.
+    pub(crate) fn new_codec(&self) -> LengthDelimitedCodec {
+        let mut builder = LengthDelimitedCodec::builder();
+        builder.length_field_length(self.length_field_length);
+        builder.max_frame_length(self.max_frame_length);
+        if self.little_endian {
+            builder.little_endian();
+        }
+        if self.length_includes_header {
+            builder.length_adjustment(-(self.length_field_length as isize));
+        }
+        builder.new_codec()
+    }
+}
+
+/// The TLS configuration of an AMS instance.
+///
+/// Inbound connections are only wrapped in TLS if a server config is provided, and outbound connections if a client
+/// config is provided. Outbound connections verify the certificate of the remote peer against its IP address.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// The certificate and key presented to peers connecting to this instance.
+    pub server: Option<std::sync::Arc<rustls::ServerConfig>>,
+    /// The trusted roots (and optional client certificate) used when connecting to a peer.
+    pub client: Option<std::sync::Arc<rustls::ClientConfig>>,
+}
+
+/// Which side of a connection the local peer is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// The remote peer connected to us.
+    Inbound,
+    /// We connected to the remote peer.
+    Outbound,
+}
+
+impl Side {
+    /// Returns the side the remote peer is on.
+    pub(crate) fn opposite(self) -> Self {
+        match self {
+            Self::Inbound => Self::Outbound,
+            Self::Outbound => Self::Inbound,
+        }
+    }
+}
+
+/// Secures a freshly opened stream and agrees on the codec with the remote peer, as configured.
+///
+/// Performed by the connection's task rather than the manager, as the remote peer may take a while to answer.
+pub(crate) struct Handshake {
+    side: Side,
+    stack: StackKind,
+    format: MessageFormat,
+    codec: CodecConfig,
+    #[cfg(feature = "tls")]
+    tls: TlsConfig,
+}
+
+impl Handshake {
+    pub fn new(side: Side, stack: StackKind, config: &AmsConfig) -> Self {
+        Self {
+            side,
+            stack,
+            format: config.format,
+            codec: config.codec,
+            #[cfg(feature = "tls")]
+            tls: config.tls.clone(),
+        }
+    }
+
+    /// The preamble carrying the protocol version, describing the codec and identifying the layers of the controller
+    /// stack and the message format, exchanged with the remote peer.
+    fn preamble(&self) -> [u8; PREAMBLE_LEN] {
+        let [a, b, c, d] = fingerprint(self.stack.layers()).to_be_bytes();
+        [
+            b'A',
+            b'M',
+            b'S',
+            PROTOCOL_VERSION,
+            self.codec.length_field_length as u8,
+            u8::from(self.codec.little_endian) | (u8::from(self.codec.length_includes_header) << 1),
+            a,
+            b,
+            c,
+            d,
+            self.format.id(),
+        ]
+    }
+
+    /// Performs the handshake over the stream to the specified peer, returning the framed stream.
+    pub async fn perform(self, stream: impl Io, addr: SocketAddr) -> std::io::Result<Transport> {
+        let local = self.preamble();
+        let mut stream: Box<dyn Io> = Box::new(stream);
+        #[cfg(feature = "tls")]
+        match (self.side, self.tls.server, self.tls.client) {
+            (Side::Inbound, Some(server), _) => {
+                stream = Box::new(
+                    tokio_rustls::TlsAcceptor::from(server)
+                        .accept(stream)
+                        .await?,
+                );
+            }
+            (Side::Outbound, _, Some(client)) => {
+                let name = rustls::pki_types::ServerName::IpAddress(addr.ip().into());
+                stream = Box::new(
+                    tokio_rustls::TlsConnector::from(client)
+                        .connect(name, stream)
+                        .await?,
+                );
+            }
+            _ => {}
+        }
+        #[cfg(not(feature = "tls"))]
+        let _ = (self.side, addr);
+
+        // Each peer sends its preamble before reading the other's, so neither waits on the other.
+        stream.write_all(&local).await?;
+        stream.flush().await?;
+        let mut remote = [0; PREAMBLE_LEN];
+        stream.read_exact(&mut remote).await?;
+        if remote[..3] != local[..3] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "remote peer is not an AMS peer",
+            ));
+        }
+        if remote[3] != PROTOCOL_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "remote peer uses protocol version {}, expected {PROTOCOL_VERSION}",
+                    remote[3]
+                ),
+            ));
+        }
+        if remote[4..6] != local[4..6] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "remote peer uses a different frame codec",
+            ));
+        }
+        if remote[6..10] != local[6..10] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "remote peer uses a different controller stack",
+            ));
+        }
+        if remote[10] != local[10] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "remote peer uses a different message format",
+            ));
+        }
+
+        Ok(Framed::new(stream, self.codec.new_codec()))
+    }
+}
+
+/// Returns the 32-bit FNV-1a hash of the layer names, identifying a controller stack independently of the platform and
+/// build.
+fn fingerprint(layers: &[&str]) -> u32 {
+    layers
+        .iter()
+        .flat_map(|name| name.bytes().chain([0]))
+        .fold(0x811c_9dc5, |hash, byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        })
+}
+
+/// Returns both ends of an in-memory framed stream, for tests.
+#[cfg(test)]
+pub(crate) fn duplex() -> (Transport, Transport) {
+    let (local, remote) = tokio::io::duplex(64 * 1024);
+    (
+        Framed::new(Box::new(local), LengthDelimitedCodec::new()),
+        Framed::new(Box::new(remote), LengthDelimitedCodec::new()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the handshake of the default configuration.
+    fn handshake() -> Handshake {
+        Handshake::new(Side::Outbound, StackKind::default(), &AmsConfig::default())
+    }
+
+    /// Performs the handshake against a remote peer sending the specified preamble, returning the error if it failed.
+    async fn handshake_with(preamble: [u8; PREAMBLE_LEN]) -> Option<String> {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        remote.write_all(&preamble).await.unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        handshake()
+            .perform(local, addr)
+            .await
+            .err()
+            .map(|err| err.to_string())
+    }
+
+    #[tokio::test]
+    async fn peers_with_the_same_preamble_are_framed() {
+        let preamble = handshake().preamble();
+        assert_eq!(handshake_with(preamble).await, None);
+    }
+
+    #[tokio::test]
+    async fn peer_with_another_protocol_version_is_rejected() {
+        let mut preamble = handshake().preamble();
+        preamble[3] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            handshake_with(preamble).await,
+            Some(format!(
+                "remote peer uses protocol version {}, expected {PROTOCOL_VERSION}",
+                PROTOCOL_VERSION + 1
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_with_another_codec_is_rejected() {
+        let mut preamble = handshake().preamble();
+        preamble[4] = 2;
+        assert_eq!(
+            handshake_with(preamble).await,
+            Some("remote peer uses a different frame codec".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_with_another_stack_is_rejected() {
+        let preamble =
+            Handshake::new(Side::Inbound, StackKind::Full, &AmsConfig::default()).preamble();
+        assert_eq!(
+            handshake_with(preamble).await,
+            Some("remote peer uses a different controller stack".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_with_another_message_format_is_rejected() {
+        let mut preamble = handshake().preamble();
+        preamble[10] = u8::MAX;
+        assert_eq!(
+            handshake_with(preamble).await,
+            Some("remote peer uses a different message format".to_string())
+        );
+    }
+}